@@ -0,0 +1,150 @@
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, bounded};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::control::Control;
+use crate::dir_scanner::DirScanPool;
+use crate::file_copier::FileCopyPool;
+use crate::stats::Stats;
+
+/// How often the pools are resized
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// Limits of the pool sizes
+const MIN_THREADS: usize = 1;
+const MAX_THREADS: usize = 64;
+
+/// Throughput has to go up by this much for an added thread to be kept
+const MIN_IMPROVEMENT: f64 = 1.05;
+
+/// Intervals to wait before trying to grow again after it didn't help
+const HOLD_INTERVALS: u32 = 6;
+
+/// Hill climbing on the size of one pool: add threads while there is a
+/// backlog and it makes things faster, remove them when they're not needed.
+struct Tuner {
+    /// Throughput during the last interval
+    last_rate: f64,
+    /// Whether we added a thread at the end of the last interval
+    grew: bool,
+    /// Intervals left before we try to grow again
+    hold: u32,
+}
+
+impl Tuner {
+    fn new() -> Tuner {
+        Tuner {
+            last_rate: 0.0,
+            grew: false,
+            hold: 0,
+        }
+    }
+
+    /// Get the new size of the pool, from the throughput during the last
+    /// interval and whether work is waiting in the queue.
+    fn step(&mut self, threads: usize, rate: f64, backlog: bool) -> usize {
+        let grew = std::mem::replace(&mut self.grew, false);
+        let last_rate = std::mem::replace(&mut self.last_rate, rate);
+
+        if !backlog {
+            // Threads are waiting for work, we have too many
+            return threads.saturating_sub(1).max(MIN_THREADS);
+        }
+        if grew && rate < last_rate * MIN_IMPROVEMENT {
+            // The last thread didn't help, remove it and wait a while
+            self.hold = HOLD_INTERVALS;
+            return threads.saturating_sub(1).max(MIN_THREADS);
+        }
+        if self.hold > 0 {
+            self.hold -= 1;
+            return threads;
+        }
+        if threads < MAX_THREADS {
+            self.grew = true;
+            return threads + 1;
+        }
+        threads
+    }
+}
+
+/// Thread resizing the pools as the sync runs, see --adaptive.
+pub struct Adaptive {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Adaptive {
+    /// Start resizing the pools.
+    ///
+    /// The scanner's throughput is entries per second, the copier's is files
+    /// per second plus MiB per second, so both small and large files count.
+    pub fn start(
+        stats: Arc<Stats>,
+        control: Arc<Control>,
+        dir_scan_pool: Arc<DirScanPool>,
+        file_copy_pool: Arc<FileCopyPool>,
+    ) -> Adaptive {
+        let (stop, stop_recv) = bounded(1);
+        let thread = std::thread::spawn(move || tune(&stats, &control, &dir_scan_pool, &file_copy_pool, stop_recv));
+        Adaptive { stop, thread }
+    }
+
+    /// Stop resizing, before the pools are shut down.
+    pub fn stop(self) {
+        drop(self.stop);
+        self.thread.join().unwrap();
+    }
+}
+
+fn tune(
+    stats: &Stats,
+    control: &Control,
+    dir_scan_pool: &Arc<DirScanPool>,
+    file_copy_pool: &Arc<FileCopyPool>,
+    stop: Receiver<()>,
+) {
+    let mut scan_tuner = Tuner::new();
+    let mut copy_tuner = Tuner::new();
+    let mut last_time = Instant::now();
+    let mut last_scanned = stats.scanned_entries();
+    let mut last_copied = (stats.copied_entries(), stats.copied_bytes());
+
+    loop {
+        if !matches!(stop.recv_timeout(INTERVAL), Err(RecvTimeoutError::Timeout)) {
+            return;
+        }
+
+        let now = Instant::now();
+        let seconds = now.duration_since(last_time).as_secs_f64();
+        let scanned = stats.scanned_entries();
+        let copied = (stats.copied_entries(), stats.copied_bytes());
+        let scan_rate = (scanned - last_scanned) as f64 / seconds;
+        let copy_rate = ((copied.0 - last_copied.0) as f64 + (copied.1 - last_copied.1) as f64 / 1048576.0) / seconds;
+        last_time = now;
+        last_scanned = scanned;
+        last_copied = copied;
+
+        // Nothing to measure while paused
+        if control.paused() {
+            scan_tuner = Tuner::new();
+            copy_tuner = Tuner::new();
+            continue;
+        }
+
+        let threads = dir_scan_pool.num_threads();
+        let new_threads = scan_tuner.step(threads, scan_rate, stats.scan_queue_depth() > 0);
+        if new_threads != threads {
+            debug!("Scanned {:.1} entries/s, resizing scan pool to {} threads", scan_rate, new_threads);
+            dir_scan_pool.set_threads(new_threads);
+        }
+
+        let threads = file_copy_pool.num_threads();
+        let new_threads = copy_tuner.step(threads, copy_rate, stats.copy_queue_depth() > 0);
+        if new_threads != threads {
+            debug!("Copied {:.1} files+MiB/s, resizing copy pool to {} threads", copy_rate, new_threads);
+            file_copy_pool.set_threads(new_threads);
+        }
+    }
+}
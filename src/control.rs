@@ -0,0 +1,220 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tracing::{error, info};
+
+use crate::dir_scanner::DirScanPool;
+use crate::file_copier::FileCopyPool;
+use crate::stats::Stats;
+use crate::throttle::{Schedule, Throttle};
+
+/// Shared state used to steer the worker pools while they run.
+pub struct Control {
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    /// Number of reasons the copies alone are paused: the target is full,
+    /// or --space-check refuse waits for the scan. Changed with `paused`
+    /// locked
+    copies_paused: AtomicUsize,
+    resumed: Condvar,
+    pub throttle: Throttle,
+}
+
+impl Control {
+    pub fn new(stats: Arc<Stats>) -> Control {
+        Control {
+            cancelled: AtomicBool::new(false),
+            paused: Mutex::new(false),
+            copies_paused: AtomicUsize::new(0),
+            resumed: Condvar::new(),
+            throttle: Throttle::new(stats),
+        }
+    }
+
+    /// Follow the time-of-day schedules of the throttle until the sync is
+    /// over.
+    pub fn start_schedule(self: &Arc<Self>) {
+        let control = self.clone();
+        std::thread::spawn(move || control.throttle.follow_schedules());
+    }
+
+    /// Stop starting new work. Operations in progress are allowed to finish,
+    /// and whatever is still queued is dropped.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        // Wake up paused threads, so they can drop their work
+        let _paused = self.paused.lock().unwrap();
+        self.resumed.notify_all();
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        *self.paused.lock().unwrap() = paused;
+        if !paused {
+            self.resumed.notify_all();
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// Block while the sync is paused, unless it gets cancelled.
+    pub fn wait_if_paused(&self) {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused && !self.cancelled() {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+    }
+
+    /// Pause the copies, until a matching `resume_copies()`.
+    pub fn pause_copies(&self) {
+        let _paused = self.paused.lock().unwrap();
+        self.copies_paused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn resume_copies(&self) {
+        let _paused = self.paused.lock().unwrap();
+        if self.copies_paused.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.resumed.notify_all();
+        }
+    }
+
+    pub fn copies_paused(&self) -> bool {
+        self.copies_paused.load(Ordering::Relaxed) > 0
+    }
+
+    /// Block while the sync or the copies are paused, unless it gets
+    /// cancelled.
+    pub fn wait_if_copies_paused(&self) {
+        let mut paused = self.paused.lock().unwrap();
+        while (*paused || self.copies_paused()) && !self.cancelled() {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+    }
+}
+
+/// Everything the control socket can act on.
+pub struct Controlled {
+    pub control: Arc<Control>,
+    pub dir_scan_pool: Arc<DirScanPool>,
+    pub file_copy_pool: Arc<FileCopyPool>,
+    pub stats: Arc<Stats>,
+}
+
+impl Controlled {
+    /// Run a command, returning the response.
+    fn command(&self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["pause"] => {
+                info!("Pausing");
+                self.control.set_paused(true);
+                Ok("ok".to_owned())
+            }
+            ["resume"] => {
+                info!("Resuming");
+                self.control.set_paused(false);
+                Ok("ok".to_owned())
+            }
+            ["cancel"] => {
+                info!("Cancelling");
+                self.control.cancel();
+                Ok("ok".to_owned())
+            }
+            ["threads", pool, count] => {
+                let count: usize = match count.parse() {
+                    Ok(c) if c > 0 => c,
+                    _ => return Err(format!("invalid number of threads {:?}", count)),
+                };
+                match *pool {
+                    "scan" => self.dir_scan_pool.set_threads(count),
+                    "copy" => self.file_copy_pool.set_threads(count),
+                    _ => return Err(format!("unknown pool {:?}, should be scan or copy", pool)),
+                }
+                Ok("ok".to_owned())
+            }
+            ["bwlimit", limit] => {
+                let schedule = Schedule::parse(limit, 1024).ok_or_else(|| format!("invalid limit {:?}", limit))?;
+                self.control.throttle.set_bandwidth(schedule);
+                Ok("ok".to_owned())
+            }
+            ["iops", limit] => {
+                let schedule = Schedule::parse(limit, 1000).ok_or_else(|| format!("invalid limit {:?}", limit))?;
+                self.control.throttle.set_iops(schedule);
+                Ok("ok".to_owned())
+            }
+            ["status"] => {
+                let mut status = self.stats.status_report();
+                if self.control.paused() {
+                    status.push_str("Paused\n");
+                } else if self.control.copies_paused() {
+                    status.push_str("Copies paused until there is space on the target\n");
+                }
+                Ok(status.trim_end().to_owned())
+            }
+            _ => Err(format!("unknown command {:?}", line.trim())),
+        }
+    }
+
+    fn handle_client(&self, stream: UnixStream) -> std::io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match self.command(&line) {
+                Ok(response) => writeln!(writer, "{}", response)?,
+                Err(e) => writeln!(writer, "error: {}", e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Listen for commands on a Unix socket, see --control.
+pub fn serve(path: &Path, controlled: Controlled) -> std::io::Result<()> {
+    // Remove a socket left over by a previous run
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Listening for commands on {:?}", path);
+
+    let controlled = Arc::new(controlled);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let controlled = controlled.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = controlled.handle_client(stream) {
+                            error!("Error on control connection: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Error accepting control connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Send a command to a running sync, for `fast-local-sync ctl`. Returns
+/// whether it succeeded.
+pub fn send_command(path: &Path, command: &str) -> std::io::Result<bool> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    print!("{}", response);
+    Ok(!response.starts_with("error:"))
+}
@@ -1,79 +1,261 @@
 use filetime::{FileTime, set_symlink_file_times};
-use std::fs::{copy, create_dir, read_link, remove_file, set_permissions, symlink_metadata};
-use std::io::ErrorKind;
-use std::os::unix::fs::{MetadataExt, lchown, symlink};
-use std::path::Path;
-use tracing::debug;
-
-// Metadata copied unconditionally
-pub fn copy_extended_metadata(source: &Path, target: &Path, is_dir: bool) -> std::io::Result<()> {
+use std::fmt::{Display, Formatter};
+use std::fs::{File, FileType, Metadata, OpenOptions, create_dir, read_link, remove_file, set_permissions, symlink_metadata};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt, lchown, symlink};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::fileflags::{copy_flags, with_flags_cleared};
+use crate::itemize::Changes;
+use crate::probe::Capabilities;
+use crate::space::is_out_of_space;
+use crate::stats::format_bytes;
+
+/// Which extended attributes to copy, by name pattern.
+///
+/// Patterns can contain `*` and `?` wildcards, e.g. `user.*`, and match the
+/// whole name. An attribute is copied
+/// if it matches one of the include patterns (or there are none), and none of
+/// the exclude patterns. `system.*` is always excluded, ACLs are copied
+/// separately.
+#[derive(Clone, Default)]
+pub struct XattrFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+#[cfg(feature = "attr")]
+impl XattrFilter {
+    pub fn matches(&self, name: &std::ffi::OsStr) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = name.as_bytes();
+        if glob_match(b"system.*", name) {
+            return false;
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|p| glob_match(p.as_bytes(), name)) {
+            return false;
+        }
+        !self.exclude.iter().any(|p| glob_match(p.as_bytes(), name))
+    }
+}
+
+#[cfg(feature = "attr")]
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+/// What to do with SELinux labels (the `security.selinux` attribute).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SelinuxMode {
+    /// Copy the label from the source, like any other attribute
+    Copy,
+    /// Don't touch the label on the target
+    Skip,
+    /// Give the target the label of its parent directory on the target side
+    Relabel,
+}
+
+/// Default for --buffer-size
+pub const DEFAULT_BUFFER_SIZE: usize = 128 * 1024;
+
+/// Alignment of the buffer, offsets and lengths for O_DIRECT
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Which attributes to copy and how to compare them.
+#[derive(Clone)]
+pub struct CopyOptions {
+    pub ownership: bool,
+    pub permissions: bool,
+    pub symlinks: bool,
+    pub acl: bool,
+    pub xattr: bool,
+    /// Modification times closer than this are considered equal
+    pub modify_window: Duration,
+    /// Set the access time of the target to the source's, rather than
+    /// leaving it alone
+    pub preserve_atime: bool,
+    /// Open source files with O_NOATIME, so reading them doesn't change
+    /// their access time
+    pub noatime: bool,
+    pub xattr_filter: XattrFilter,
+    pub selinux: SelinuxMode,
+    /// Copy inode flags (chattr)
+    pub fileflags: bool,
+    /// Clear immutable and append-only flags on target entries that need
+    /// to be replaced or deleted
+    pub force_change: bool,
+    /// Skip source entries that have the nodump flag
+    pub skip_nodump: bool,
+    /// Drop copied data from the page cache, see --no-cache
+    pub no_cache: bool,
+    /// Use O_DIRECT for files at least this large, see --direct-io
+    pub direct_io_threshold: Option<u64>,
+    /// Size of the chunks files are copied in
+    pub buffer_size: usize,
+    /// Allocate the space of target files before writing them
+    pub preallocate: bool,
+}
+
+impl CopyOptions {
+    pub fn from_capabilities(caps: &Capabilities) -> CopyOptions {
+        CopyOptions {
+            ownership: caps.ownership,
+            permissions: caps.permissions,
+            symlinks: caps.symlinks,
+            acl: caps.acl,
+            xattr: caps.xattr,
+            modify_window: caps.timestamp_granularity,
+            preserve_atime: true,
+            noatime: false,
+            xattr_filter: XattrFilter::default(),
+            selinux: SelinuxMode::Copy,
+            fileflags: false,
+            force_change: false,
+            skip_nodump: false,
+            no_cache: false,
+            direct_io_threshold: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            preallocate: true,
+        }
+    }
+}
+
+// Metadata copied unconditionally, only writing what differs
+//
+// Returns what was changed on the target.
+#[cfg(any(feature = "acl", feature = "attr"))]
+pub fn copy_extended_metadata(source: &Path, target: &Path, file_type: FileType, options: &CopyOptions) -> std::io::Result<Changes> {
+    let mut changes = Changes::default();
+
+    // Linux has no ACLs on symlinks, and getfacl() would follow them
     #[cfg(feature = "acl")]
-    {
+    if options.acl && !file_type.is_symlink() {
         use exacl::{AclOption, getfacl, setfacl};
 
-        let acl = getfacl(source, Some(AclOption::ACCESS_ACL))?;
-        setfacl(&[target], &acl, Some(AclOption::ACCESS_ACL))?;
+        let mut kinds = vec![AclOption::ACCESS_ACL];
+        if file_type.is_dir() {
+            kinds.push(AclOption::DEFAULT_ACL);
+        }
 
-        if is_dir {
-            let default_acl = getfacl(source, Some(AclOption::DEFAULT_ACL))?;
-            setfacl(&[target], &default_acl, Some(AclOption::DEFAULT_ACL))?;
+        for kind in kinds {
+            let mut source_acl = getfacl(source, Some(kind))?;
+            let mut target_acl = getfacl(target, Some(kind))?;
+            source_acl.sort();
+            target_acl.sort();
+            if source_acl != target_acl {
+                debug!("ACL differs, setting {:?}", target);
+                setfacl(&[target], &source_acl, Some(kind))?;
+                changes.acl = true;
+            }
         }
     }
 
     #[cfg(feature = "attr")]
-    {
-        use std::collections::HashSet;
-        use std::os::unix::ffi::OsStrExt;
+    if options.xattr {
+        use std::collections::HashMap;
+        use std::ffi::OsString;
         use xattr::{get, list, remove, set};
 
-        let mut seen_attrs = HashSet::new();
+        const SELINUX: &str = "security.selinux";
 
-        for name in list(source)? {
-            let name_b = &name.as_bytes();
-            if name_b.len() >= 7 && &name_b[0..7] == b"system." {
-                continue;
+        let read_attrs = |path: &Path| -> std::io::Result<HashMap<OsString, Vec<u8>>> {
+            let mut attrs = HashMap::new();
+            for name in list(path)? {
+                if !options.xattr_filter.matches(&name) {
+                    continue;
+                }
+                if options.selinux != SelinuxMode::Copy && name == SELINUX {
+                    continue;
+                }
+
+                if let Some(value) = get(path, &name)? {
+                    attrs.insert(name, value);
+                }
             }
+            Ok(attrs)
+        };
 
-            if let Some(value) = get(source, &name)? {
-                set(target, &name, &value)?;
-                seen_attrs.insert(name);
+        let mut source_attrs = read_attrs(source)?;
+        let mut target_attrs = read_attrs(target)?;
+
+        if options.selinux == SelinuxMode::Relabel {
+            if let Some(parent) = target.parent() {
+                if let Some(label) = get(parent, SELINUX)? {
+                    source_attrs.insert(SELINUX.into(), label);
+                    if let Some(current) = get(target, SELINUX)? {
+                        target_attrs.insert(SELINUX.into(), current);
+                    }
+                }
             }
         }
 
-        for name in list(target)? {
-            let name_b = &name.as_bytes();
-            if name_b.len() >= 7 && &name_b[0..7] == b"system." {
-                continue;
+        for (name, value) in source_attrs {
+            if target_attrs.remove(&name).as_ref() != Some(&value) {
+                debug!("Setting xattr {:?} on {:?}", name, target);
+                set(target, &name, &value)?;
+                changes.xattr = true;
             }
+        }
 
-            if !seen_attrs.contains(&name) {
-                remove(target, name)?;
-            }
+        // Whatever is left is not on the source
+        for name in target_attrs.into_keys() {
+            debug!("Removing xattr {:?} from {:?}", name, target);
+            remove(target, name)?;
+            changes.xattr = true;
         }
     }
 
-    Ok(())
+    #[cfg(not(feature = "acl"))]
+    let _ = file_type;
+
+    Ok(changes)
 }
 
-// Metadata copied when the file is copied
-fn copy_metadata(source: &Path, target: &Path) -> std::io::Result<()> {
-    // Get metadata of source
-    let metadata = symlink_metadata(source)?;
+#[cfg(not(any(feature = "acl", feature = "attr")))]
+pub fn copy_extended_metadata(_source: &Path, _target: &Path, _file_type: FileType, _options: &CopyOptions) -> std::io::Result<Changes> {
+    Ok(Changes::default())
+}
 
+// Metadata copied when the file is copied
+fn copy_metadata(source: &Path, target: &Path, metadata: &Metadata, options: &CopyOptions) -> std::io::Result<Changes> {
     // Copy attributes
-    lchown(target, Some(metadata.uid()), Some(metadata.gid()))?;
-    if !metadata.is_symlink() {
+    // Changing the owner clears setuid/setgid bits and file capabilities
+    // (security.capability), so it has to happen before the permissions and
+    // extended attributes are set
+    if options.ownership {
+        lchown(target, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    if options.permissions && !metadata.is_symlink() {
         set_permissions(target, metadata.permissions())?;
     }
-    let mtime = FileTime::from_last_modification_time(&metadata);
-    set_symlink_file_times(target, mtime, mtime)?;
+    let mtime = FileTime::from_last_modification_time(metadata);
+    let atime = if options.preserve_atime {
+        FileTime::from_last_access_time(metadata)
+    } else {
+        FileTime::from_last_access_time(&symlink_metadata(target)?)
+    };
+    set_symlink_file_times(target, atime, mtime)?;
 
-    copy_extended_metadata(source, target, metadata.is_dir())?;
+    copy_extended_metadata(source, target, metadata.file_type(), options)
+}
 
-    Ok(())
+/// Whether an error was caused by the source entry being deleted while we
+/// were syncing it.
+pub fn source_vanished(error: &std::io::Error, source: &Path) -> bool {
+    error.kind() == ErrorKind::NotFound
+        && matches!(symlink_metadata(source), Err(e) if e.kind() == ErrorKind::NotFound)
 }
 
-pub fn copy_directory(source: &Path, target: &Path) -> std::io::Result<()> {
+pub fn copy_directory(source: &Path, target: &Path, options: &CopyOptions) -> std::io::Result<Changes> {
     debug!("copy_directory {:?} {:?}", source, target);
 
     // Create the directory if it does not exist
@@ -83,35 +265,392 @@ pub fn copy_directory(source: &Path, target: &Path) -> std::io::Result<()> {
         Err(e) => return Err(e),
     }
 
-    copy_metadata(source, target)
+    let metadata = symlink_metadata(source)?;
+    copy_metadata(source, target, &metadata, options)
 }
 
-pub fn copy_file(source: &Path, target: &Path) -> std::io::Result<u64> {
-    debug!("copy_file {:?} {:?}", source, target);
+fn open_source(source: &Path, options: &CopyOptions, flags: i32) -> std::io::Result<File> {
+    if options.noatime {
+        // O_NOATIME is only allowed for the owner of the file (or root),
+        // fall back to a normal open if we're not allowed
+        match OpenOptions::new().read(true).custom_flags(flags | libc::O_NOATIME).open(source) {
+            Ok(f) => return Ok(f),
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    OpenOptions::new().read(true).custom_flags(flags).open(source)
+}
 
-    let source_metadata = symlink_metadata(source)?;
+fn open_target(target: &Path, metadata: &Metadata, flags: i32) -> std::io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(metadata.permissions().mode())
+        .custom_flags(flags)
+        .open(target)
+}
+
+/// Open a file with O_DIRECT, or without it if the filesystem doesn't
+/// support it. Returns whether O_DIRECT is used.
+fn open_direct(open: impl Fn(i32) -> std::io::Result<File>, path: &Path) -> std::io::Result<(File, bool)> {
+    match open(libc::O_DIRECT) {
+        Ok(f) => Ok((f, true)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            debug!("O_DIRECT not supported for {:?}", path);
+            Ok((open(0)?, false))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn clear_direct(file: &File) -> std::io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether a file has holes, i.e. uses less space than its size.
+fn is_sparse(metadata: &Metadata) -> bool {
+    metadata.blocks() * 512 < metadata.len()
+}
+
+/// Preallocation refused by the filesystem, see `preallocate()`.
+#[derive(Debug)]
+struct PreallocateError {
+    size: u64,
+    path: PathBuf,
+    source: std::io::Error,
+}
+
+impl Display for PreallocateError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "can't preallocate {} bytes ({}) for {:?}: {}", self.size, format_bytes(self.size), self.path, self.source)
+    }
+}
+
+impl std::error::Error for PreallocateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Allocate the space for the whole file before writing it, so it doesn't
+/// get fragmented, and so we fail now rather than halfway through if there
+/// is not enough space. Filesystems that don't support it are skipped.
+///
+/// The error keeps the kind of the original one, so ENOSPC is still
+/// recognized by `is_out_of_space()`.
+fn preallocate(file: &File, target: &Path, size: u64) -> std::io::Result<()> {
+    if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, size as i64) } == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOSYS) => {
+            debug!("Preallocation not supported for {:?}", target);
+            Ok(())
+        }
+        _ => Err(std::io::Error::new(
+            error.kind(),
+            PreallocateError {
+                size,
+                path: target.to_owned(),
+                source: error,
+            },
+        )),
+    }
+}
 
-    let size = if source_metadata.is_symlink() {
-        let link = read_link(source)?;
-        debug!("copy_file symlink {:?} -> {:?}", link, target);
-        match remove_file(target) {
-            Ok(()) => {}
-            Err(e) if e.kind() == ErrorKind::NotFound => {}
+/// Fill the buffer, unless the end of the file is reached. Returns the
+/// number of bytes read.
+fn read_chunk(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
             Err(e) => return Err(e),
         }
-        symlink(link, target)?;
+    }
+    Ok(filled)
+}
+
+/// Write a range of the target to disk and drop it from the page cache, for
+/// --no-cache.
+///
+/// `sync_file_range()` is only advisory on some filesystems, errors saying
+/// it's not supported are ignored.
+fn drop_written(file: &File, offset: u64, length: usize) -> std::io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    if unsafe { libc::sync_file_range(fd, offset as i64, length as i64, flags) } != 0 {
+        let error = std::io::Error::last_os_error();
+        if !matches!(error.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS | libc::ESPIPE | libc::EOPNOTSUPP)) {
+            return Err(error);
+        }
+    }
+    unsafe { libc::posix_fadvise(fd, offset as i64, length as i64, libc::POSIX_FADV_DONTNEED) };
+    Ok(())
+}
+
+/// Start writing a range of the target to disk, without waiting.
+fn start_writeback(file: &File, offset: u64, length: usize) {
+    unsafe { libc::sync_file_range(file.as_raw_fd(), offset as i64, length as i64, libc::SYNC_FILE_RANGE_WRITE) };
+}
+
+/// Copy with `copy_file_range()` in chunks of `chunk` bytes, so filesystems
+/// can reflink or copy server-side. Stops at the end of the source, or as soon
+/// as the kernel can't copy between these files, the caller then copies the
+/// rest itself. Returns the number of bytes copied.
+fn copy_range(source: &File, target: &File, chunk: usize, progress: &dyn Fn(u64)) -> std::io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let ret = unsafe {
+            libc::copy_file_range(
+                source.as_raw_fd(),
+                std::ptr::null_mut(),
+                target.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                0,
+            )
+        };
+        if ret < 0 {
+            let error = std::io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // Same as std::io::copy: the read/write fallback reports the
+                // error if it's a real one
+                Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL | libc::EPERM | libc::EBADF) => {
+                    debug!("copy_file_range not usable: {}", error);
+                    return Ok(copied);
+                }
+                _ => return Err(error),
+            }
+        }
+        if ret == 0 {
+            // End of the source, or a file like in /proc that reports no data
+            return Ok(copied);
+        }
+        copied += ret as u64;
+        progress(ret as u64);
+    }
+}
+
+/// Copy the contents of a file in chunks, calling `progress` after each one
+/// so the watchdog can tell a long copy from a hung one.
+fn copy_contents(
+    source: &Path,
+    target: &Path,
+    metadata: &Metadata,
+    options: &CopyOptions,
+    progress: &dyn Fn(u64),
+) -> std::io::Result<u64> {
+    let direct = options.direct_io_threshold.is_some_and(|t| metadata.len() >= t);
+    let (mut source_file, mut target_file, target_direct) = if direct {
+        let (source_file, _) = open_direct(|flags| open_source(source, options, flags), source)?;
+        let (target_file, target_direct) = open_direct(|flags| open_target(target, metadata, flags), target)?;
+        (source_file, target_file, target_direct)
+    } else {
+        (open_source(source, options, 0)?, open_target(target, metadata, 0)?, false)
+    };
+
+    // Preallocating would fill the holes of sparse files
+    let preallocated = options.preallocate && metadata.len() > 0 && !is_sparse(metadata);
+    if !preallocated {
+        return write_contents(&mut source_file, &mut target_file, direct, target_direct, options, progress);
+    }
+    let result = preallocate(&target_file, target, metadata.len())
+        .and_then(|()| write_contents(&mut source_file, &mut target_file, direct, target_direct, options, progress));
+    // Free the space allocated past the end: the source shrank, or the copy
+    // failed halfway
+    match result {
+        Ok(copied) if copied < metadata.len() => target_file.set_len(copied)?,
+        Ok(_) => {}
+        Err(_) => {
+            if let Err(e) = target_file.metadata().and_then(|m| target_file.set_len(m.len())) {
+                warn!("Can't free the space preallocated for {:?}: {}", target, e);
+            }
+        }
+    }
+    result
+}
+
+/// The copy loop of `copy_contents()`, once the files are open. Returns the
+/// number of bytes copied.
+fn write_contents(
+    source_file: &mut File,
+    target_file: &mut File,
+    direct: bool,
+    mut target_direct: bool,
+    options: &CopyOptions,
+    progress: &dyn Fn(u64),
+) -> std::io::Result<u64> {
+    // O_DIRECT needs an aligned buffer and aligned lengths
+    let buffer_size = if direct {
+        options.buffer_size.next_multiple_of(DIRECT_IO_ALIGNMENT)
+    } else {
+        options.buffer_size
+    };
+    let mut buffer = vec![0u8; buffer_size + DIRECT_IO_ALIGNMENT];
+    let start = buffer.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+    let buffer = &mut buffer[start..start + buffer_size];
+
+    // The read/write loop is only needed to control the page cache, or for
+    // what copy_file_range() couldn't copy
+    let mut copied = if direct || options.no_cache {
         0
-    } else if source_metadata.is_file() {
-        debug!("copy_file regular file {:?} -> {:?}", source, target);
-        copy(source, target)?
     } else {
-        return Err(std::io::Error::new(
-            ErrorKind::Other,
-            format!("Don't know how to copy entry that's not a symlink or a file: {:?}", source),
-        ));
+        copy_range(source_file, target_file, buffer_size, progress)?
     };
+    // With --no-cache, the previous chunk, that is being written out
+    let mut writing: Option<(u64, usize)> = None;
+    loop {
+        let read = read_chunk(source_file, buffer)?;
+        if read == 0 {
+            break;
+        }
+        if target_direct && read % DIRECT_IO_ALIGNMENT != 0 {
+            // Last chunk of the file, can't be written with O_DIRECT
+            clear_direct(target_file)?;
+            target_direct = false;
+        }
+        target_file.write_all(&buffer[..read])?;
 
-    copy_metadata(source, target)?;
+        if options.no_cache {
+            unsafe {
+                libc::posix_fadvise(source_file.as_raw_fd(), copied as i64, read as i64, libc::POSIX_FADV_DONTNEED);
+            }
+            // Start writing this chunk, and wait for the previous one, so
+            // there are never more than two chunks of dirty pages
+            start_writeback(target_file, copied, read);
+            if let Some((offset, length)) = writing {
+                drop_written(target_file, offset, length)?;
+            }
+            writing = Some((copied, read));
+        }
 
-    Ok(size)
+        copied += read as u64;
+        progress(read as u64);
+    }
+    if let Some((offset, length)) = writing {
+        drop_written(target_file, offset, length)?;
+    }
+    Ok(copied)
+}
+
+/// Copy a file or symlink, calling `progress` as data gets written.
+pub fn copy_file(
+    source: &Path,
+    target: &Path,
+    options: &CopyOptions,
+    progress: &dyn Fn(u64),
+) -> std::io::Result<(u64, Changes)> {
+    debug!("copy_file {:?} {:?}", source, target);
+
+    let source_metadata = symlink_metadata(source)?;
+
+    with_flags_cleared(target, options, options.fileflags, || {
+        let size = if source_metadata.is_symlink() {
+            let link = read_link(source)?;
+            debug!("copy_file symlink {:?} -> {:?}", link, target);
+            match remove_file(target) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            symlink(link, target)?;
+            0
+        } else if source_metadata.is_file() {
+            debug!("copy_file regular file {:?} -> {:?}", source, target);
+            match copy_contents(source, target, &source_metadata, options, progress) {
+                Ok(size) => size,
+                Err(e) => {
+                    if is_out_of_space(&e) {
+                        // Don't leave a truncated file behind, and give the
+                        // space it took back
+                        match remove_file(target) {
+                            Ok(()) => {}
+                            Err(e) if e.kind() == ErrorKind::NotFound => {}
+                            Err(e) => warn!("Can't remove partial copy {:?}: {}", target, e),
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        } else {
+            return Err(std::io::Error::other(
+                format!("Don't know how to copy entry that's not a symlink or a file: {:?}", source),
+            ));
+        };
+
+        let mut changes = copy_metadata(source, target, &source_metadata, options)?;
+
+        if options.fileflags {
+            changes.flags = copy_flags(source, target, &source_metadata)?;
+        }
+
+        Ok((size, changes))
+    })
+}
+
+#[cfg(all(test, feature = "attr"))]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::{XattrFilter, glob_match};
+
+    #[test]
+    fn wildcards() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"user.foo"));
+        assert!(glob_match(b"user.*", b"user."));
+        assert!(glob_match(b"user.*", b"user.foo.bar"));
+        assert!(glob_match(b"*.foo", b"user.foo"));
+        assert!(glob_match(b"user.*o*", b"user.foo"));
+        assert!(!glob_match(b"user.*", b"trusted.foo"));
+
+        assert!(glob_match(b"user.?", b"user.a"));
+        assert!(glob_match(b"user.f?o", b"user.foo"));
+        assert!(!glob_match(b"user.?", b"user."));
+        assert!(!glob_match(b"user.?", b"user.ab"));
+    }
+
+    #[test]
+    fn anchored() {
+        assert!(glob_match(b"user.foo", b"user.foo"));
+        assert!(!glob_match(b"user.foo", b"user.foobar"));
+        assert!(!glob_match(b"user.foo", b"xuser.foo"));
+        assert!(!glob_match(b"foo", b"user.foo"));
+        assert!(!glob_match(b"user", b"user.foo"));
+    }
+
+    #[test]
+    fn namespaces() {
+        let filter = XattrFilter {
+            include: Vec::new(),
+            exclude: vec!["security.*".to_owned()],
+        };
+        assert!(filter.matches(OsStr::new("user.foo")));
+        assert!(filter.matches(OsStr::new("trusted.foo")));
+        assert!(!filter.matches(OsStr::new("security.selinux")));
+        assert!(!filter.matches(OsStr::new("security.capability")));
+        assert!(!filter.matches(OsStr::new("system.posix_acl_access")));
+
+        let filter = XattrFilter {
+            include: vec!["user.*".to_owned(), "security.capability".to_owned()],
+            exclude: vec!["user.tmp.*".to_owned()],
+        };
+        assert!(filter.matches(OsStr::new("user.foo")));
+        assert!(filter.matches(OsStr::new("security.capability")));
+        assert!(!filter.matches(OsStr::new("security.selinux")));
+        assert!(!filter.matches(OsStr::new("user.tmp.x")));
+        assert!(!filter.matches(OsStr::new("trusted.foo")));
+    }
 }
@@ -1,4 +1,4 @@
-use crossbeam::channel::{Receiver, Sender, unbounded};
+use crossbeam::channel::{Receiver, Sender, bounded, select, unbounded};
 use std::collections::HashSet;
 use std::ffi::OsString;
 use std::fs::{Metadata, read_dir, remove_dir, remove_file, symlink_metadata};
@@ -6,246 +6,469 @@ use std::io::ErrorKind;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::thread::{JoinHandle, sleep};
-use std::time::Duration;
-use tracing::{debug, error, info};
+use std::thread::JoinHandle;
+use tracing::{debug, info, warn};
 
-use crate::copy::{copy_directory, copy_extended_metadata};
+use crate::control::Control;
+use crate::copy::{CopyOptions, copy_directory, copy_extended_metadata, source_vanished};
+use crate::error_log::Operation;
+use crate::itemize::{Action, Changes};
 use crate::file_copier::FileCopyPool;
+use crate::retry::RetryPolicy;
+use crate::pending::Pending;
+use crate::space::{MAX_SPACE_RETRIES, is_out_of_space};
+use crate::fileflags::{DirectoryFlags, FS_NODUMP_FL, clear_protective_flags, copy_flags, get_flags, has_flags, with_flags_cleared};
 use crate::stats::Stats;
 
+enum ScanItem {
+    /// Scan a directory, and whether its target might already exist
+    Directory(PathBuf, bool),
+    /// Sync a single entry
+    Entry(PathBuf),
+}
+
 pub struct DirScanPool {
     source: PathBuf,
     target: PathBuf,
-    queue_send: Sender<(PathBuf, bool)>,
-    queue_recv: Receiver<(PathBuf, bool)>,
-    enqueued: Arc<AtomicUsize>,
+    queue_send: Sender<ScanItem>,
+    queue_recv: Receiver<ScanItem>,
+    pending: Pending,
     file_copier: Arc<FileCopyPool>,
-    threads: Mutex<Vec<(JoinHandle<()>, Arc<AtomicBool>)>>,
+    options: CopyOptions,
+    retry: RetryPolicy,
+    control: Arc<Control>,
+    /// Directories whose flags are set at the very end, since immutable or
+    /// append-only flags would prevent filling them. Holds the flags to
+    /// restore if they were cleared and we are not copying flags
+    directory_flags: Arc<DirectoryFlags>,
+    /// Threads and their stop channels, which make them exit when dropped
+    threads: Mutex<Vec<(JoinHandle<()>, Sender<()>)>>,
+    /// Threads that were told to stop by `set_threads()`
+    retired_threads: Mutex<Vec<JoinHandle<()>>>,
     stats: Arc<Stats>,
 }
 
 impl DirScanPool {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         source: &Path,
         target: &Path,
         num_threads: usize,
         file_copier: Arc<FileCopyPool>,
+        options: CopyOptions,
+        retry: RetryPolicy,
+        directory_flags: Arc<DirectoryFlags>,
+        control: Arc<Control>,
         stats: Arc<Stats>,
     ) -> Arc<DirScanPool> {
         // Create work queue
         let (send, recv) = unbounded();
-        let enqueued = Arc::new(AtomicUsize::new(0));
 
         let pool = Arc::new(DirScanPool {
             source: source.to_owned(),
             target: target.to_owned(),
             queue_send: send,
             queue_recv: recv,
-            enqueued,
+            pending: Pending::new(),
             file_copier,
+            options,
+            retry,
+            control,
+            directory_flags,
             threads: Mutex::new(Vec::new()),
+            retired_threads: Mutex::new(Vec::new()),
             stats,
         });
 
         // Start threads
-        {
-            let mut threads = pool.threads.lock().unwrap();
-            for _ in 0..num_threads {
-                let pool2 = pool.clone();
-                let cond = Arc::new(AtomicBool::new(false));
-                let cond2 = cond.clone();
-                let thread = std::thread::spawn(move || {
-                    dir_scan_thread(
-                        pool2,
-                        cond2,
-                    )
-                });
-                threads.push((thread, cond));
-            }
-            info!("Created {} dir scanner threads", num_threads);
-        }
+        pool.set_threads(num_threads);
+        info!("Created {} dir scanner threads", num_threads);
 
         pool
     }
 
+    /// Start or stop threads to get to this number. Stopped threads finish
+    /// the directory they are scanning first.
+    pub fn set_threads(self: &Arc<Self>, num_threads: usize) {
+        let mut threads = self.threads.lock().unwrap();
+        while threads.len() < num_threads {
+            let pool = self.clone();
+            let (stop_send, stop_recv) = bounded(0);
+            let thread = std::thread::spawn(move || {
+                dir_scan_thread(
+                    pool,
+                    stop_recv,
+                )
+            });
+            threads.push((thread, stop_send));
+        }
+        if threads.len() > num_threads {
+            let removed = threads.split_off(num_threads);
+            self.retired_threads.lock().unwrap().extend(removed.into_iter().map(|(thread, _)| thread));
+        }
+        debug!("{} dir scanner threads", threads.len());
+        self.stats.set_scan_threads(threads.len());
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.threads.lock().unwrap().len()
+    }
+
     pub fn add(&self, path: PathBuf) {
         debug!("scanner add {:?}", path);
-        self.enqueued.fetch_add(1, Ordering::Relaxed);
-        self.queue_send.send((path, true)).unwrap();
+        self.pending.add();
+        self.stats.scan_queued();
+        self.queue_send.send(ScanItem::Directory(path, true)).unwrap();
     }
 
     pub fn add_no_check(&self, path: PathBuf) {
         debug!("scanner add_no_check {:?}", path);
-        self.enqueued.fetch_add(1, Ordering::Relaxed);
-        self.queue_send.send((path, false)).unwrap();
+        self.pending.add();
+        self.stats.scan_queued();
+        self.queue_send.send(ScanItem::Directory(path, false)).unwrap();
+    }
+
+    pub fn add_entry(&self, path: PathBuf) {
+        debug!("scanner add_entry {:?}", path);
+        self.pending.add();
+        self.stats.scan_queued();
+        self.queue_send.send(ScanItem::Entry(path)).unwrap();
     }
 
+    /// Wait until all the queued directories have been scanned.
     pub fn join(&self) {
-        let enqueued = &*self.enqueued;
-        loop {
-            debug!("dir scanner enqueued {}", enqueued.load(Ordering::Relaxed));
-            if enqueued.load(Ordering::Relaxed) == 0 {
-                return;
-            }
-            sleep(Duration::from_secs(2));
+        self.pending.wait();
+    }
+
+    /// Stop the threads and wait for them to exit, once the queue is empty.
+    pub fn shutdown(&self) {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for (thread, stop) in threads {
+            drop(stop);
+            thread.join().unwrap();
+        }
+        for thread in std::mem::take(&mut *self.retired_threads.lock().unwrap()) {
+            thread.join().unwrap();
         }
     }
 }
 
-fn metadata_equal(a: &Metadata, b: &Metadata) -> bool {
-    if a.file_type() != b.file_type() {
-        return false;
-    }
-    if a.is_file() && a.len() != b.len() {
-        return false;
+/// Compare the metadata of entries of the same type, empty if up-to-date.
+fn compare_metadata(a: &Metadata, b: &Metadata, options: &CopyOptions) -> Changes {
+    let (a_mtime, b_mtime) = (a.modified().unwrap(), b.modified().unwrap());
+    let mtime_diff = a_mtime.duration_since(b_mtime)
+        .or_else(|_| b_mtime.duration_since(a_mtime))
+        .unwrap();
+    Changes {
+        size: a.is_file() && a.len() != b.len(),
+        perms: options.permissions && a.mode() != b.mode(),
+        owner: options.ownership && a.uid() != b.uid(),
+        group: options.ownership && a.gid() != b.gid(),
+        mtime: mtime_diff > options.modify_window,
+        ..Default::default()
     }
-    if a.mode() != b.mode() {
-        return false;
-    }
-    if a.uid() != b.uid() {
-        return false;
-    }
-    if a.gid() != b.gid() {
-        return false;
-    }
-    if a.modified().unwrap() != b.modified().unwrap() {
-        return false;
-    }
-    return true;
 }
 
 fn dir_scan_thread(
     pool: Arc<DirScanPool>,
-    stop_condition: Arc<AtomicBool>,
+    stop: Receiver<()>,
 ) {
     let pool = &*pool;
     let file_copier = &pool.file_copier;
-    let stop_condition = &*stop_condition;
     let source = &pool.source;
     let target = &pool.target;
+    let options = &pool.options;
+    let retry = &pool.retry;
+    let worker = pool.stats.register_worker("scan");
+    let worker = &worker;
 
-    let dir_scan = |dir_path: PathBuf, check_target: bool| {
-        let mut seen_source_entries = HashSet::<OsString>::new();
+    // Sync one entry whose source exists
+    let sync_entry = |entry_path: PathBuf, source_metadata: Metadata, check_target: bool| {
+        let source_path = source.join(&entry_path);
 
-        let source_dir = match read_dir(source.join(&dir_path)) {
-            Ok(d) => d,
-            Err(e) => {
-                error!("Error reading directory: {}", e);
-                pool.stats.add_errors(1);
-                return;
-            }
-        };
+        if source_metadata.is_symlink() && !options.symlinks {
+            debug!("Target doesn't support symlinks, skipping {:?}", entry_path);
+            pool.stats.add_skipped_entries(1);
+            pool.stats.add_scanned_entries(1);
+            return;
+        }
 
-        for source_entry in source_dir {
-            let source_entry = match source_entry {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Error reading directory entry: {}", e);
-                    pool.stats.add_errors(1);
+        if options.skip_nodump && has_flags(&source_metadata) {
+            match get_flags(&source_path) {
+                Ok(flags) if flags & FS_NODUMP_FL != 0 => {
+                    debug!("Entry has nodump flag, skipping {:?}", entry_path);
+                    pool.stats.add_skipped_entries(1);
+                    pool.stats.add_scanned_entries(1);
                     return;
                 }
-            };
-            debug!("source path={:?} file_name={:?}", source_entry.path(), source_entry.file_name());
-            let source_path = source_entry.path();
-            let source_metadata = match source_entry.metadata() {
-                Ok(m) => m,
+                Ok(_) => {}
+                // Filesystem doesn't support flags
+                Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => {}
                 Err(e) => {
-                    error!("Error reading source entry: {}", e);
-                    pool.stats.add_errors(1);
+                    pool.stats.record_error(&entry_path, Operation::ReadFlags, &e);
                     return;
                 }
-            };
-            let entry_path = dir_path.join(source_entry.file_name());
-            seen_source_entries.insert(source_entry.file_name().to_owned());
+            }
+        }
 
-            let target_path = target.join(&entry_path);
-            debug!("target_path {:?}", target_path);
+        let target_path = target.join(&entry_path);
+        debug!("target_path {:?}", target_path);
 
-            let copy = || {
-                if source_metadata.is_dir() {
-                    if let Err(e) = copy_directory(&source_path, &target_path) {
-                        error!("Error copying directory: {}", e);
-                        pool.stats.add_errors(1);
-                        return;
-                    }
+        let file_type = source_metadata.file_type();
+        let size = if source_metadata.is_file() { source_metadata.len() } else { 0 };
 
-                    pool.add_no_check(entry_path.clone());
-                } else {
-                    file_copier.add(entry_path.clone());
+        // Create the directory or update its metadata, waiting for space if
+        // the target is full
+        let copy_dir = || {
+            let mut space_retries = 0;
+            loop {
+                let result = retry.run(&entry_path, &pool.stats, worker, || copy_directory(&source_path, &target_path, options));
+                if matches!(&result, Err(e) if is_out_of_space(e))
+                    && space_retries < MAX_SPACE_RETRIES
+                    && file_copier.wait_for_space(source_metadata.len())
+                {
+                    space_retries += 1;
+                    continue;
                 }
-            };
+                break result;
+            }
+        };
 
-            if !check_target {
-                // Fast path: if the subtree doesn't exist on the target,
-                // no need to check each entry
-                copy();
+        // `replaced` is true if the target had a different type
+        let copy = |replaced: bool| {
+            let (action, changes) = if replaced {
+                (Action::TypeChanged, Changes::type_changed())
             } else {
-                match symlink_metadata(&target_path) {
-                    Err(e) if e.kind() == ErrorKind::NotFound => {
-                        // Target does not exist, copy
-                        debug!("Target does not exist, copy {:?}", entry_path);
-                        copy();
+                (Action::Transferred, Changes::created())
+            };
+            if source_metadata.is_dir() {
+                worker.busy(&entry_path, Operation::CopyDirectory, 0);
+                pool.control.throttle.operation(worker);
+                match copy_dir() {
+                    Ok(_) => pool.stats.itemize(&entry_path, file_type, action, &changes, 0),
+                    Err(e) if source_vanished(&e, &source_path) => {
+                        warn!("Source directory vanished: {:?}", entry_path);
+                        pool.stats.add_vanished_entries(1);
+                        return;
                     }
                     Err(e) => {
-                        error!("Error reading target entry: {}", e);
-                        pool.stats.add_errors(1);
-                        continue;
+                        pool.stats.record_error(&entry_path, Operation::CopyDirectory, &e);
+                        return;
                     }
-                    Ok(target_metadata) => {
-                        // Compare metadata
-                        if source_metadata.file_type() != target_metadata.file_type() {
-                            debug!("Different file type, removing target {:?}", target_path);
-                            if target_metadata.is_dir() {
-                                if let Err(e) = remove_dir_recursive(&target_path, &pool.stats) {
-                                    error!("Error removing target directory: {}", e);
-                                    pool.stats.add_errors(1);
-                                    continue;
+                }
+                pool.directory_flags.defer(entry_path.clone(), None);
+
+                pool.add_no_check(entry_path.clone());
+            } else {
+                file_copier.add(entry_path.clone(), file_type, changes, size);
+            }
+        };
+
+        if !check_target {
+            // Fast path: if the subtree doesn't exist on the target,
+            // no need to check each entry
+            copy(false);
+        } else {
+            match symlink_metadata(&target_path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    // Target does not exist, copy
+                    debug!("Target does not exist, copy {:?}", entry_path);
+                    copy(false);
+                }
+                Err(e) => {
+                    pool.stats.record_error(&entry_path, Operation::ReadTarget, &e);
+                    return;
+                }
+                Ok(target_metadata) => {
+                    // Compare metadata
+                    if file_type != target_metadata.file_type() {
+                        debug!("Different file type, removing target {:?}", target_path);
+                        if let Err(e) = remove_target(&target_path, &target_metadata, options, &pool.stats) {
+                            pool.stats.record_error(&entry_path, Operation::Remove, &e);
+                            return;
+                        }
+                        pool.stats.itemize(&entry_path, target_metadata.file_type(), Action::Deleted, &Changes::default(), target_metadata.len());
+                        // Target no longer exists, copy
+                        copy(true);
+                    } else if source_metadata.is_dir() {
+                        let cleared = match clear_protective_flags(&target_path, options) {
+                            Ok(c) => c,
+                            Err(e) => {
+                                pool.stats.record_error(&entry_path, Operation::SetFlags, &e);
+                                return;
+                            }
+                        };
+                        pool.directory_flags.defer(entry_path.clone(), cleared);
+                        let changes = compare_metadata(&source_metadata, &target_metadata, options);
+                        if !changes.is_empty() {
+                            worker.busy(&entry_path, Operation::CopyDirectory, 0);
+                            pool.control.throttle.operation(worker);
+                            match copy_dir() {
+                                Ok(extended) => pool.stats.itemize(&entry_path, file_type, Action::Attributes, &(changes | extended), 0),
+                                Err(e) if source_vanished(&e, &source_path) => {
+                                    warn!("Source directory vanished: {:?}", entry_path);
+                                    pool.stats.add_vanished_entries(1);
+                                    return;
                                 }
-                            } else {
-                                if let Err(e) = remove_file(&target_path) {
-                                    error!("Error removing target entry: {}", e);
-                                    pool.stats.add_errors(1);
-                                    continue;
+                                Err(e) => {
+                                    pool.stats.record_error(&entry_path, Operation::CopyDirectory, &e);
+                                    return;
                                 }
-                                pool.stats.add_removed(1, target_metadata.len());
                             }
-                            // Target no longer exists, copy
-                            copy();
-                        } else if source_metadata.is_dir() {
-                            if !metadata_equal(&source_metadata, &target_metadata) {
-                                if let Err(e) = copy_directory(&source_path, &target_path) {
-                                    error!("Error copying directory: {}", e);
-                                    pool.stats.add_errors(1);
-                                    continue;
+                        } else {
+                            match copy_extended_metadata(&source_path, &target_path, file_type, options) {
+                                Ok(extended) if !extended.is_empty() => {
+                                    pool.stats.add_updated_metadata_entries(1);
+                                    pool.stats.itemize(&entry_path, file_type, Action::Attributes, &extended, 0);
+                                }
+                                Ok(_) => {}
+                                Err(e) if source_vanished(&e, &source_path) => {
+                                    warn!("Source directory vanished: {:?}", entry_path);
+                                    pool.stats.add_vanished_entries(1);
+                                    return;
+                                }
+                                Err(e) => {
+                                    pool.stats.record_error(&entry_path, Operation::CopyMetadata, &e);
                                 }
                             }
-                            // Recurse
-                            pool.add(entry_path.clone());
-                        } else if !metadata_equal(&source_metadata, &target_metadata) {
+                        }
+                        // Recurse
+                        pool.add(entry_path.clone());
+                    } else {
+                        let changes = compare_metadata(&source_metadata, &target_metadata, options);
+                        if !changes.is_empty() {
                             // Copy non-directory entry (file, link, ...)
-                            file_copier.add(entry_path.clone());
+                            if target_metadata.is_file() {
+                                pool.stats.add_replaced(target_metadata.len());
+                            }
+                            file_copier.add(entry_path.clone(), file_type, changes, size);
                         } else {
-                            // Copy extended metadata
-                            if let Err(e) = copy_extended_metadata(&source_path, &target_path, source_metadata.is_dir()) {
-                                error!("Error copying extended metadata: {}", e);
+                            // Copy extended metadata, if it differs
+                            let update = || -> std::io::Result<Changes> {
+                                let mut extended = copy_extended_metadata(&source_path, &target_path, file_type, options)?;
+                                if options.fileflags {
+                                    extended.flags = copy_flags(&source_path, &target_path, &source_metadata)?;
+                                }
+                                Ok(extended)
+                            };
+                            let result = match update() {
+                                // The target might be immutable, only clear
+                                // its flags if it gets in the way
+                                Err(e) if options.force_change && e.raw_os_error() == Some(libc::EPERM) => {
+                                    with_flags_cleared(&target_path, options, options.fileflags, update)
+                                }
+                                result => result,
+                            };
+                            match result {
+                                Ok(extended) if !extended.is_empty() => {
+                                    pool.stats.add_updated_metadata_entries(1);
+                                    pool.stats.itemize(&entry_path, file_type, Action::Attributes, &extended, 0);
+                                }
+                                Ok(_) => pool.stats.add_skipped_entries(1),
+                                Err(e) if source_vanished(&e, &source_path) => {
+                                    warn!("Source entry vanished: {:?}", entry_path);
+                                    pool.stats.add_vanished_entries(1);
+                                }
+                                Err(e) => {
+                                    pool.stats.record_error(&entry_path, Operation::CopyMetadata, &e);
+                                }
                             }
-                            pool.stats.add_skipped_entries(1);
                         }
                     }
                 }
             }
+        }
 
-            pool.stats.add_scanned_entries(1);
+        pool.stats.add_scanned_entries(1);
+    };
+
+    // Remove an entry from the target that is not in the source
+    let remove_extraneous = |entry_path: &Path, target_metadata: &Metadata| {
+        debug!("Removing entry, not in source: {:?}", entry_path);
+        pool.control.wait_if_paused();
+        if pool.control.cancelled() {
+            return;
+        }
+        worker.busy(entry_path, Operation::Remove, target_metadata.len());
+        pool.control.throttle.operation(worker);
+        match remove_target(&target.join(entry_path), target_metadata, options, &pool.stats) {
+            Ok(()) => pool.stats.itemize(entry_path, target_metadata.file_type(), Action::Deleted, &Changes::default(), target_metadata.len()),
+            Err(e) => pool.stats.record_error(entry_path, Operation::Remove, &e),
+        }
+    };
+
+    let dir_scan = |dir_path: PathBuf, check_target: bool| {
+        let mut seen_source_entries = HashSet::<OsString>::new();
+        // If we couldn't list the whole source directory, we don't know
+        // which target entries to remove
+        let mut source_listing_complete = true;
+
+        let source_dir_path = source.join(&dir_path);
+        worker.busy(&dir_path, Operation::ReadDirectory, 0);
+        pool.control.throttle.operation(worker);
+        let source_dir = match retry.run(&dir_path, &pool.stats, worker, || read_dir(&source_dir_path)) {
+            Ok(d) => d,
+            Err(e) if source_vanished(&e, &source_dir_path) => {
+                warn!("Source directory vanished: {:?}", dir_path);
+                pool.stats.add_vanished_entries(1);
+                return;
+            }
+            Err(e) => {
+                pool.stats.record_error(&dir_path, Operation::ReadDirectory, &e);
+                return;
+            }
+        };
+
+        for source_entry in source_dir {
+            // Directories can be huge, don't wait for the end of this one
+            pool.control.wait_if_paused();
+            if pool.control.cancelled() {
+                // Leave the rest of the directory alone
+                return;
+            }
+            let source_entry = match source_entry {
+                Ok(s) => s,
+                Err(e) => {
+                    pool.stats.record_error(&dir_path, Operation::ReadDirectory, &e);
+                    source_listing_complete = false;
+                    continue;
+                }
+            };
+            debug!("source path={:?} file_name={:?}", source_entry.path(), source_entry.file_name());
+            let entry_path = dir_path.join(source_entry.file_name());
+            worker.busy(&entry_path, Operation::ReadSource, 0);
+            pool.control.throttle.operation(worker);
+            let source_metadata = match source_entry.metadata() {
+                Ok(m) => m,
+                Err(e) if source_vanished(&e, &source_entry.path()) => {
+                    // Not added to the seen entries, so the target gets removed
+                    warn!("Source entry vanished: {:?}", entry_path);
+                    pool.stats.add_vanished_entries(1);
+                    continue;
+                }
+                Err(e) => {
+                    pool.stats.record_error(&entry_path, Operation::ReadSource, &e);
+                    seen_source_entries.insert(source_entry.file_name());
+                    continue;
+                }
+            };
+            seen_source_entries.insert(source_entry.file_name());
+
+            sync_entry(entry_path, source_metadata, check_target);
+        }
+
+        if !source_listing_complete {
+            warn!("Not removing extraneous entries from {:?}, source listing failed", dir_path);
+            return;
         }
 
         // Remove unseen entries in target
-        let target_dir = match read_dir(target.join(&dir_path)) {
+        worker.busy(&dir_path, Operation::ReadTarget, 0);
+        pool.control.throttle.operation(worker);
+        let target_dir = match retry.run(&dir_path, &pool.stats, worker, || read_dir(target.join(&dir_path))) {
             Ok(d) => d,
             Err(e) => {
-                error!("Error reading target directory: {}", e);
-                pool.stats.add_errors(1);
+                pool.stats.record_error(&dir_path, Operation::ReadTarget, &e);
                 return;
             }
         };
@@ -254,69 +477,99 @@ fn dir_scan_thread(
             let target_entry = match target_entry {
                 Ok(s) => s,
                 Err(e) => {
-                    error!("Error reading target directory entry: {}", e);
-                    pool.stats.add_errors(1);
-                    return;
+                    pool.stats.record_error(&dir_path, Operation::ReadTarget, &e);
+                    continue;
                 }
             };
             if !seen_source_entries.contains(&target_entry.file_name()) {
+                let entry_path = dir_path.join(target_entry.file_name());
                 let target_metadata = match target_entry.metadata() {
                     Ok(m) => m,
                     Err(e) => {
-                        error!("Error reading target directory entry: {}", e);
-                        pool.stats.add_errors(1);
-                        return;
+                        pool.stats.record_error(&entry_path, Operation::ReadTarget, &e);
+                        continue;
                     }
                 };
 
-                if target_metadata.is_dir() {
-                    debug!("Removing directory, not in source: {:?}", target_entry.path());
-                    if let Err(e) = remove_dir_recursive(&target_entry.path(), &pool.stats) {
-                        error!("Error removing target directory: {}", e);
-                        pool.stats.add_errors(1);
-                        continue;
-                    }
-                } else {
-                    debug!("Removing file, not in source: {:?}", target_entry.path());
-                    if let Err(e) = remove_file(target_entry.path()) {
-                        error!("Error removing target entry: {}", e);
-                        pool.stats.add_errors(1);
-                        continue;
-                    }
-                    pool.stats.add_removed(1, target_metadata.len());
+                remove_extraneous(&entry_path, &target_metadata);
+            }
+        }
+    };
+
+    // Sync a single entry, e.g. one that failed in a previous run
+    let entry_sync = |entry_path: PathBuf| {
+        worker.busy(&entry_path, Operation::ReadSource, 0);
+        pool.control.throttle.operation(worker);
+        match symlink_metadata(source.join(&entry_path)) {
+            Ok(source_metadata) => sync_entry(entry_path, source_metadata, true),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                match symlink_metadata(target.join(&entry_path)) {
+                    Ok(target_metadata) => remove_extraneous(&entry_path, &target_metadata),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => pool.stats.record_error(&entry_path, Operation::ReadTarget, &e),
                 }
             }
+            Err(e) => pool.stats.record_error(&entry_path, Operation::ReadSource, &e),
         }
     };
 
     loop {
-        let (path, check_target) = match pool.queue_recv.recv_timeout(Duration::from_secs(5)) {
-            Ok(p) => p,
-            Err(_) => {
-                // Check if we should stop
-                if stop_condition.load(Ordering::Relaxed) {
-                    debug!("Stop condition true, exiting thread");
-                    return;
-                }
-                continue;
+        let item = select! {
+            recv(pool.queue_recv) -> item => item.unwrap(),
+            recv(stop) -> _ => {
+                debug!("Stopped, exiting thread");
+                return;
             }
         };
+        pool.stats.scan_started();
+        pool.control.wait_if_paused();
 
-        debug!("Scanning {:?}, check_target={}", path, check_target);
-        dir_scan(path, check_target);
+        if pool.control.cancelled() {
+            // Drop the queued work
+            pool.pending.done();
+            continue;
+        }
 
-        pool.enqueued.fetch_sub(1, Ordering::Relaxed);
+        match item {
+            ScanItem::Directory(path, check_target) => {
+                debug!("Scanning {:?}, check_target={}", path, check_target);
+                dir_scan(path, check_target);
+            }
+            ScanItem::Entry(path) => {
+                debug!("Syncing entry {:?}", path);
+                entry_sync(path);
+            }
+        }
+
+        worker.idle();
+        pool.pending.done();
+    }
+}
+
+fn remove_target(path: &Path, metadata: &Metadata, options: &CopyOptions, stats: &Stats) -> std::io::Result<()> {
+    if metadata.is_dir() {
+        remove_dir_recursive(path, options, stats)
+    } else {
+        remove_entry(path, options)?;
+        stats.add_removed(1, metadata.len());
+        Ok(())
     }
 }
 
-fn remove_dir_recursive(path: &Path, stats: &Stats) -> std::io::Result<()> {
+fn remove_entry(path: &Path, options: &CopyOptions) -> std::io::Result<()> {
+    clear_protective_flags(path, options)?;
+    remove_file(path)
+}
+
+fn remove_dir_recursive(path: &Path, options: &CopyOptions, stats: &Stats) -> std::io::Result<()> {
+    clear_protective_flags(path, options)?;
     for entry in read_dir(path)? {
         let entry = entry?;
         if entry.file_type()?.is_dir() {
-            remove_dir_recursive(&entry.path(), stats)?;
+            remove_dir_recursive(&entry.path(), options, stats)?;
         } else {
             let size = entry.metadata()?.len();
-            remove_file(&entry.path())?;
+            remove_entry(&entry.path(), options)?;
             stats.add_removed(1, size);
         };
     }
@@ -0,0 +1,184 @@
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::json;
+
+/// What we were doing when an error happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    ReadDirectory,
+    ReadSource,
+    ReadTarget,
+    CopyDirectory,
+    CopyFile,
+    CopyMetadata,
+    ReadFlags,
+    SetFlags,
+    Remove,
+}
+
+impl Operation {
+    /// Identifier used in the error log
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::ReadDirectory => "read_directory",
+            Operation::ReadSource => "read_source",
+            Operation::ReadTarget => "read_target",
+            Operation::CopyDirectory => "copy_directory",
+            Operation::CopyFile => "copy_file",
+            Operation::CopyMetadata => "copy_metadata",
+            Operation::ReadFlags => "read_flags",
+            Operation::SetFlags => "set_flags",
+            Operation::Remove => "remove",
+        }
+    }
+
+    /// Description used in log messages, "Error <description> <path>"
+    pub fn description(&self) -> &'static str {
+        match self {
+            Operation::ReadDirectory => "reading directory",
+            Operation::ReadSource => "reading source entry",
+            Operation::ReadTarget => "reading target entry",
+            Operation::CopyDirectory => "copying directory",
+            Operation::CopyFile => "copying file",
+            Operation::CopyMetadata => "copying extended metadata of",
+            Operation::ReadFlags => "reading flags of",
+            Operation::SetFlags => "setting flags of",
+            Operation::Remove => "removing target entry",
+        }
+    }
+}
+
+/// The errno of an error, also when it wraps an OS error to add context.
+pub fn errno(error: &std::io::Error) -> Option<i32> {
+    error.raw_os_error().or_else(|| error.get_ref()?.source()?.downcast_ref::<std::io::Error>()?.raw_os_error())
+}
+
+/// Failures written as JSON lines, so they can be processed or retried.
+///
+/// Each line has the relative path, the operation, the errno (or null) and
+/// the message. If the path is not valid UTF-8, it is also given as hex in
+/// `path_hex`.
+pub struct ErrorLog {
+    file: Mutex<File>,
+}
+
+impl ErrorLog {
+    pub fn create(path: &Path) -> std::io::Result<ErrorLog> {
+        Ok(ErrorLog {
+            file: Mutex::new(File::create(path)?),
+        })
+    }
+
+    pub fn write(&self, path: &Path, operation: Operation, error: &std::io::Error) {
+        let mut line = String::new();
+        line.push_str("{\"path\": ");
+        json::write_string(&mut line, &path.to_string_lossy());
+        if path.to_str().is_none() {
+            line.push_str(", \"path_hex\": \"");
+            for b in path.as_os_str().as_bytes() {
+                write!(line, "{:02x}", b).unwrap();
+            }
+            line.push('"');
+        }
+        line.push_str(", \"operation\": ");
+        json::write_string(&mut line, operation.name());
+        match errno(error) {
+            Some(errno) => write!(line, ", \"errno\": {}", errno).unwrap(),
+            None => line.push_str(", \"errno\": null"),
+        }
+        line.push_str(", \"message\": ");
+        json::write_string(&mut line, &error.to_string());
+        line.push_str("}\n");
+
+        // Write the whole line at once, so it's there even if we crash later
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            tracing::error!("Error writing to error log: {}", e);
+        }
+    }
+}
+
+/// Read the paths from an error log, for --retry-from.
+pub fn read_paths(path: &Path) -> std::io::Result<BTreeSet<PathBuf>> {
+    let mut paths = BTreeSet::new();
+    let file = BufReader::new(File::open(path)?);
+    for (num, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid error log entry on line {}", num + 1),
+        );
+        let object = json::parse_flat_object(&line).ok_or_else(invalid)?;
+        let entry_path = if let Some(Some(hex)) = object.get("path_hex") {
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(invalid)?;
+            PathBuf::from(OsStr::from_bytes(&bytes))
+        } else if let Some(Some(p)) = object.get("path") {
+            PathBuf::from(p)
+        } else {
+            return Err(invalid());
+        };
+        // Paths have to be relative, and stay within the tree
+        if !entry_path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(invalid());
+        }
+        paths.insert(entry_path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    use super::{ErrorLog, Operation, read_paths};
+
+    #[test]
+    fn read_back_paths() {
+        let log_path = std::env::temp_dir().join(format!("fast-local-sync-test-{}.jsonl", std::process::id()));
+        let paths = [
+            PathBuf::from("plain/file.txt"),
+            PathBuf::from("quote\"back\\slash\ttab"),
+            PathBuf::from("new\nline/\u{1}control"),
+            PathBuf::from("unicode/caf\u{e9} \u{1f600}"),
+            PathBuf::from(OsStr::from_bytes(b"not/utf8\xff\xfe")),
+        ];
+
+        let log = ErrorLog::create(&log_path).unwrap();
+        log.write(&paths[0], Operation::CopyFile, &std::io::Error::from_raw_os_error(libc::EIO));
+        log.write(&paths[1], Operation::Remove, &std::io::Error::other("message with \"quotes\"\n"));
+        for path in &paths[2..] {
+            log.write(path, Operation::ReadSource, &std::io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        drop(log);
+
+        let read = read_paths(&log_path);
+        std::fs::remove_file(&log_path).unwrap();
+        assert_eq!(read.unwrap(), paths.into_iter().collect::<BTreeSet<_>>());
+    }
+
+    #[test]
+    fn reject_paths_outside_tree() {
+        let log_path = std::env::temp_dir().join(format!("fast-local-sync-test-{}-outside.jsonl", std::process::id()));
+        std::fs::write(&log_path, "{\"path\": \"../etc/passwd\", \"operation\": \"copy_file\", \"errno\": 5, \"message\": \"\"}\n").unwrap();
+        let read = read_paths(&log_path);
+        std::fs::remove_file(&log_path).unwrap();
+        assert!(read.is_err());
+    }
+}
@@ -1,122 +1,261 @@
-use crossbeam::channel::{Receiver, Sender, bounded};
+use crossbeam::channel::{Receiver, SendTimeoutError, Sender, TrySendError, bounded, select};
+use std::fs::FileType;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::thread::sleep;
 use std::thread::JoinHandle;
-use std::time::Duration;
-use tracing::{debug, error, info};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
-use crate::copy::copy_file;
+use crate::control::Control;
+use crate::copy::{CopyOptions, copy_file, source_vanished};
+use crate::error_log::Operation;
+use crate::itemize::{Action, Changes};
+use crate::pending::Pending;
+use crate::retry::RetryPolicy;
+use crate::space::{FullPolicy, MAX_SPACE_RETRIES, OutOfSpace, is_out_of_space};
 use crate::stats::Stats;
 
+/// How often a scanner blocked on a full queue checks whether copies got
+/// paused
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+struct CopyItem {
+    path: PathBuf,
+    file_type: FileType,
+    /// Why the entry is copied, for itemized output
+    changes: Changes,
+    /// Size of the source, for progress
+    size: u64,
+}
+
 pub struct FileCopyPool {
     source: PathBuf,
     target: PathBuf,
-    queue_send: Sender<PathBuf>,
-    queue_recv: Receiver<PathBuf>,
-    enqueued: Arc<AtomicUsize>,
-    threads: Mutex<Vec<(JoinHandle<()>, Arc<AtomicBool>)>>,
+    queue_send: Sender<CopyItem>,
+    queue_recv: Receiver<CopyItem>,
+    /// Files added while copies were paused, which would have blocked the
+    /// scanners on the full queue. Moved to the queue as it empties
+    deferred: Mutex<Vec<CopyItem>>,
+    pending: Pending,
+    options: CopyOptions,
+    retry: RetryPolicy,
+    out_of_space: OutOfSpace,
+    control: Arc<Control>,
+    /// Threads and their stop channels, which make them exit when dropped
+    threads: Mutex<Vec<(JoinHandle<()>, Sender<()>)>>,
+    /// Threads that were told to stop by `set_threads()`
+    retired_threads: Mutex<Vec<JoinHandle<()>>>,
     stats: Arc<Stats>,
 }
 
 impl FileCopyPool {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         source: &Path,
         target: &Path,
         num_threads: usize,
+        options: CopyOptions,
+        retry: RetryPolicy,
+        full_policy: FullPolicy,
+        control: Arc<Control>,
         stats: Arc<Stats>,
     ) -> Arc<FileCopyPool> {
         // Create work queue
         let (send, recv) = bounded(4096);
-        let enqueued = Arc::new(AtomicUsize::new(0));
 
         let pool = Arc::new(FileCopyPool {
             source: source.to_owned(),
             target: target.to_owned(),
             queue_send: send,
             queue_recv: recv,
-            enqueued,
+            deferred: Mutex::new(Vec::new()),
+            pending: Pending::new(),
+            options,
+            retry,
+            out_of_space: OutOfSpace::new(full_policy),
+            control,
             threads: Mutex::new(Vec::new()),
+            retired_threads: Mutex::new(Vec::new()),
             stats,
         });
 
-        #[cfg(feature = "acl")]
-        info!("Will copy ACLs");
+        if pool.options.acl {
+            info!("Will copy ACLs");
+        }
 
-        #[cfg(feature = "attr")]
-        info!("Will copy extended attributes");
+        if pool.options.xattr {
+            info!("Will copy extended attributes");
+        }
 
         // Start threads
-        {
-            let mut threads = pool.threads.lock().unwrap();
-            for _ in 0..num_threads {
-                let pool2 = pool.clone();
-                let cond = Arc::new(AtomicBool::new(false));
-                let cond2 = cond.clone();
-                let thread = std::thread::spawn(move || {
-                    file_copy_thread(
-                        pool2,
-                        cond2,
-                    )
-                });
-                threads.push((thread, cond));
-            }
-            info!("Created {} file copy threads", num_threads);
-        }
+        pool.set_threads(num_threads);
+        info!("Created {} file copy threads", num_threads);
 
         pool
     }
 
-    pub fn add(&self, path: PathBuf) {
-        debug!("copier add {:?}", path);
-        self.enqueued.fetch_add(1, Ordering::Relaxed);
-        self.stats.add_queued_copy_entries(1);
-        self.queue_send.send(path).unwrap();
+    /// Start or stop threads to get to this number. Stopped threads finish
+    /// the file they are copying first.
+    pub fn set_threads(self: &Arc<Self>, num_threads: usize) {
+        let mut threads = self.threads.lock().unwrap();
+        while threads.len() < num_threads {
+            let pool = self.clone();
+            let (stop_send, stop_recv) = bounded(0);
+            let thread = std::thread::spawn(move || {
+                file_copy_thread(
+                    pool,
+                    stop_recv,
+                )
+            });
+            threads.push((thread, stop_send));
+        }
+        if threads.len() > num_threads {
+            let removed = threads.split_off(num_threads);
+            self.retired_threads.lock().unwrap().extend(removed.into_iter().map(|(thread, _)| thread));
+        }
+        debug!("{} file copy threads", threads.len());
+        self.stats.set_copy_threads(threads.len());
     }
 
-    pub fn join(&self) {
-        let enqueued = &*self.enqueued;
+    pub fn num_threads(&self) -> usize {
+        self.threads.lock().unwrap().len()
+    }
+
+    pub fn add(&self, path: PathBuf, file_type: FileType, changes: Changes, size: u64) {
+        if self.control.cancelled() {
+            return;
+        }
+        debug!("copier add {:?}", path);
+        self.pending.add();
+        self.stats.add_queued_copy(1, size);
+        let mut item = CopyItem { path, file_type, changes, size };
+        // While the target is full, the scanners have to go on, deleting
+        // entries frees up space
+        if self.control.copies_paused() {
+            self.deferred.lock().unwrap().push(item);
+            return;
+        }
+        self.requeue_deferred();
         loop {
-            debug!("file copier enqueued {}", enqueued.load(Ordering::Relaxed));
-            if enqueued.load(Ordering::Relaxed) == 0 {
+            match self.queue_send.send_timeout(item, PAUSE_CHECK_INTERVAL) {
+                Ok(()) => return,
+                Err(SendTimeoutError::Timeout(i)) if self.control.copies_paused() => {
+                    self.deferred.lock().unwrap().push(i);
+                    return;
+                }
+                Err(SendTimeoutError::Timeout(i)) => item = i,
+                Err(SendTimeoutError::Disconnected(_)) => unreachable!(),
+            }
+        }
+    }
+
+    /// Move the deferred files to the queue, as many as fit without
+    /// blocking.
+    fn requeue_deferred(&self) {
+        let mut deferred = self.deferred.lock().unwrap();
+        while let Some(item) = deferred.pop() {
+            if let Err(TrySendError::Full(item)) = self.queue_send.try_send(item) {
+                deferred.push(item);
                 return;
             }
-            sleep(Duration::from_secs(2));
+        }
+    }
+
+    /// Called after creating something failed because the target is full.
+    /// Returns whether to try again, see OutOfSpace::wait_for_space.
+    pub fn wait_for_space(&self, needed: u64) -> bool {
+        self.out_of_space.wait_for_space(&self.target, needed, &self.control, &self.stats)
+    }
+
+    /// Wait until all the queued files have been copied. Files can't be
+    /// added concurrently, so the scan has to be over.
+    pub fn join(&self) {
+        // The copy threads requeue the rest as they go
+        self.requeue_deferred();
+        self.pending.wait();
+    }
+
+    /// Stop the threads and wait for them to exit, once the queue is empty.
+    pub fn shutdown(&self) {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for (thread, stop) in threads {
+            drop(stop);
+            thread.join().unwrap();
+        }
+        for thread in std::mem::take(&mut *self.retired_threads.lock().unwrap()) {
+            thread.join().unwrap();
         }
     }
 }
 
 fn file_copy_thread(
     pool: Arc<FileCopyPool>,
-    stop_condition: Arc<AtomicBool>,
+    stop: Receiver<()>,
 ) {
     let pool = &*pool;
+    let worker = pool.stats.register_worker("copy");
 
     loop {
-        let path = match pool.queue_recv.recv_timeout(Duration::from_secs(5)) {
-            Ok(p) => p,
-            Err(_) => {
-                // Check if we should stop
-                if stop_condition.load(Ordering::Relaxed) {
-                    return;
-                }
-                continue;
-            }
+        let item = select! {
+            recv(pool.queue_recv) -> item => item.unwrap(),
+            recv(stop) -> _ => return,
         };
 
-        let source_path = pool.source.join(&path);
-        let target_path = pool.target.join(&path);
+        pool.stats.copy_started();
+        pool.control.wait_if_copies_paused();
+        if pool.control.cancelled() {
+            // Drop the queued work
+            pool.stats.copy_finished(item.size);
+            pool.requeue_deferred();
+            pool.pending.done();
+            continue;
+        }
+
+        let path = &item.path;
+        let source_path = pool.source.join(path);
+        let target_path = pool.target.join(path);
 
         debug!("copy {:?} -> {:?}", source_path, target_path);
+        worker.busy(path, Operation::CopyFile, item.size);
+        pool.control.throttle.operation(&worker);
+        let start = Instant::now();
 
-        match copy_file(&source_path, &target_path) {
-            Err(e) => error!("Error copying file: {}", e),
-            Ok(size) => {
+        let mut space_retries = 0;
+        let result = loop {
+            let result = pool.retry.run(path, &pool.stats, &worker, || {
+                copy_file(&source_path, &target_path, &pool.options, &|bytes| {
+                    worker.advance(bytes);
+                    pool.control.throttle.consume(bytes, &worker);
+                    pool.control.wait_if_copies_paused();
+                })
+            });
+            // On ENOSPC, wait for space and try again
+            if matches!(&result, Err(e) if is_out_of_space(e))
+                && space_retries < MAX_SPACE_RETRIES
+                && pool.wait_for_space(item.size)
+            {
+                space_retries += 1;
+                continue;
+            }
+            break result;
+        };
+        match result {
+            Err(e) if source_vanished(&e, &source_path) => {
+                warn!("Source file vanished: {:?}", path);
+                pool.stats.add_vanished_entries(1);
+            }
+            Err(e) => pool.stats.record_error(path, Operation::CopyFile, &e),
+            Ok((size, changes)) => {
                 pool.stats.add_copied(1, size);
+                pool.stats.observe_copy(size, start.elapsed());
+                let action = if item.changes.type_changed { Action::TypeChanged } else { Action::Transferred };
+                pool.stats.itemize(path, item.file_type, action, &(item.changes | changes), size);
             }
         }
 
-        pool.enqueued.fetch_sub(1, Ordering::Relaxed);
+        worker.idle();
+        pool.stats.copy_finished(item.size);
+        pool.requeue_deferred();
+        pool.pending.done();
     }
 }
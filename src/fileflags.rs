@@ -0,0 +1,202 @@
+use std::fs::{File, Metadata, OpenOptions, symlink_metadata};
+use std::io::ErrorKind;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::copy::CopyOptions;
+use crate::error_log::Operation;
+use crate::stats::Stats;
+
+// From linux/fs.h
+pub const FS_SYNC_FL: u32 = 0x00000008;
+pub const FS_IMMUTABLE_FL: u32 = 0x00000010;
+pub const FS_APPEND_FL: u32 = 0x00000020;
+pub const FS_NODUMP_FL: u32 = 0x00000040;
+pub const FS_NOATIME_FL: u32 = 0x00000080;
+pub const FS_DIRSYNC_FL: u32 = 0x00010000;
+
+/// Flags we copy from source to target with --fileflags
+const COPIED_FLAGS: u32 = FS_SYNC_FL | FS_IMMUTABLE_FL | FS_APPEND_FL | FS_NODUMP_FL | FS_NOATIME_FL | FS_DIRSYNC_FL;
+
+/// Flags that prevent modifying or deleting an entry
+pub const PROTECTIVE_FLAGS: u32 = FS_IMMUTABLE_FL | FS_APPEND_FL;
+
+fn open(path: &Path) -> std::io::Result<File> {
+    // Flags can be read and changed through a read-only descriptor, even
+    // on immutable files. O_NONBLOCK avoids hanging on FIFOs
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW | libc::O_NOCTTY)
+        .open(path)
+}
+
+fn ioctl_get(file: &File) -> std::io::Result<u32> {
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+fn ioctl_set(file: &File, flags: u32) -> std::io::Result<()> {
+    let flags = flags as libc::c_int;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether the metadata is for an entry that can have flags.
+///
+/// We can't open symlinks and special files without side effects.
+pub fn has_flags(metadata: &Metadata) -> bool {
+    metadata.is_file() || metadata.is_dir()
+}
+
+pub fn get_flags(path: &Path) -> std::io::Result<u32> {
+    ioctl_get(&open(path)?)
+}
+
+/// Copy the flags from the source entry to the target entry, if they differ.
+///
+/// This has to happen last, since immutable and append-only flags prevent
+/// further changes.
+pub fn copy_flags(source: &Path, target: &Path, metadata: &Metadata) -> std::io::Result<bool> {
+    if !has_flags(metadata) {
+        return Ok(false);
+    }
+
+    let source_flags = get_flags(source)? & COPIED_FLAGS;
+    let target_file = open(target)?;
+    let target_flags = ioctl_get(&target_file)?;
+    if target_flags & COPIED_FLAGS == source_flags {
+        return Ok(false);
+    }
+
+    debug!("Setting flags {:x} on {:?}", source_flags, target);
+    ioctl_set(&target_file, (target_flags & !COPIED_FLAGS) | source_flags)?;
+    Ok(true)
+}
+
+/// Clear immutable and append-only flags from a target entry about to be
+/// replaced or deleted.
+///
+/// Returns the previous flags if they were changed, so they can be restored.
+pub fn clear_protective_flags(path: &Path, options: &CopyOptions) -> std::io::Result<Option<u32>> {
+    if !options.force_change {
+        return Ok(None);
+    }
+
+    match symlink_metadata(path) {
+        Ok(m) if !has_flags(&m) => return Ok(None),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let file = open(path)?;
+    let flags = match ioctl_get(&file) {
+        Ok(f) => f,
+        // Filesystem doesn't support flags, so they can't be in the way
+        Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if flags & PROTECTIVE_FLAGS == 0 {
+        return Ok(None);
+    }
+
+    debug!("Clearing immutable/append-only flags on {:?}", path);
+    ioctl_set(&file, flags & !PROTECTIVE_FLAGS)?;
+    Ok(Some(flags))
+}
+
+/// Put back flags removed by `clear_protective_flags()`.
+pub fn restore_flags(path: &Path, flags: u32) -> std::io::Result<()> {
+    ioctl_set(&open(path)?, flags)
+}
+
+/// Run an operation that changes a target entry, clearing its protective
+/// flags first with --force-change and putting them back afterwards, even if
+/// the operation fails.
+///
+/// If `sets_flags`, a successful operation already set the flags it wants
+/// (--fileflags), so they are only put back on failure.
+pub fn with_flags_cleared<T>(
+    path: &Path,
+    options: &CopyOptions,
+    sets_flags: bool,
+    operation: impl FnOnce() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let cleared = clear_protective_flags(path, options)?;
+    let result = operation();
+    if let Some(flags) = cleared {
+        if result.is_err() || !sets_flags {
+            if let Err(e) = restore_flags(path, flags) {
+                if result.is_ok() {
+                    return Err(e);
+                }
+                // A failed copy can remove the entry
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Can't restore flags of {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Flags of target directories, set once the directories have been filled:
+/// the source's with --fileflags, or the ones --force-change cleared.
+///
+/// Also applied when the sync stalls or is aborted, so directories don't stay
+/// unprotected.
+pub struct DirectoryFlags {
+    source: PathBuf,
+    target: PathBuf,
+    fileflags: bool,
+    /// Directories and the flags cleared on them
+    deferred: Mutex<Vec<(PathBuf, Option<u32>)>>,
+}
+
+impl DirectoryFlags {
+    pub fn new(source: &Path, target: &Path, options: &CopyOptions) -> DirectoryFlags {
+        DirectoryFlags {
+            source: source.to_owned(),
+            target: target.to_owned(),
+            fileflags: options.fileflags,
+            deferred: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn defer(&self, path: PathBuf, cleared: Option<u32>) {
+        if self.fileflags || cleared.is_some() {
+            self.deferred.lock().unwrap().push((path, cleared));
+        }
+    }
+
+    /// Set the flags of the directories deferred so far.
+    pub fn apply(&self, stats: &Stats) {
+        let mut deferred = std::mem::take(&mut *self.deferred.lock().unwrap());
+        // Deepest first
+        deferred.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        for (path, cleared) in deferred {
+            let source_path = self.source.join(&path);
+            let target_path = self.target.join(&path);
+            let result = if self.fileflags {
+                symlink_metadata(&source_path)
+                    .and_then(|m| copy_flags(&source_path, &target_path, &m))
+                    .map(|_| ())
+            } else if let Some(flags) = cleared {
+                restore_flags(&target_path, flags)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                stats.record_error(&path, Operation::SetFlags, &e);
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs::FileType;
+use std::io::Write;
+use std::ops::BitOr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::error;
+
+/// What differs between a source entry and its target, and why it was
+/// updated.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Changes {
+    /// The target did not exist
+    pub created: bool,
+    /// The target had a different type, and was replaced
+    pub type_changed: bool,
+    pub size: bool,
+    pub mtime: bool,
+    pub perms: bool,
+    pub owner: bool,
+    pub group: bool,
+    pub acl: bool,
+    pub xattr: bool,
+    pub flags: bool,
+}
+
+impl Changes {
+    pub fn created() -> Changes {
+        Changes {
+            created: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn type_changed() -> Changes {
+        Changes {
+            type_changed: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+}
+
+impl BitOr for Changes {
+    type Output = Changes;
+
+    fn bitor(self, other: Changes) -> Changes {
+        Changes {
+            created: self.created || other.created,
+            type_changed: self.type_changed || other.type_changed,
+            size: self.size || other.size,
+            mtime: self.mtime || other.mtime,
+            perms: self.perms || other.perms,
+            owner: self.owner || other.owner,
+            group: self.group || other.group,
+            acl: self.acl || other.acl,
+            xattr: self.xattr || other.xattr,
+            flags: self.flags || other.flags,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// The entry was written, its content for files
+    Transferred,
+    /// The entry replaced one of a different type
+    TypeChanged,
+    /// Only attributes were changed
+    Attributes,
+    /// The entry was removed from the target
+    Deleted,
+}
+
+/// Change string of rsync's --itemize-changes, e.g. ">f.st......". Inode
+/// flags have no column there, see %f.
+fn itemize_string(file_type: FileType, action: Action, changes: &Changes) -> String {
+    if action == Action::Deleted {
+        return "*deleting  ".to_owned();
+    }
+
+    let mut s = String::with_capacity(11);
+    s.push(match action {
+        Action::Transferred | Action::TypeChanged if file_type.is_file() => '>',
+        Action::Transferred | Action::TypeChanged => 'c',
+        _ => '.',
+    });
+    s.push(if file_type.is_file() {
+        'f'
+    } else if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'L'
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        'D'
+    } else {
+        'S'
+    });
+    if action == Action::TypeChanged {
+        s.push_str("T++++++++");
+    } else if changes.created {
+        s.push_str("+++++++++");
+    } else {
+        for (changed, c) in [
+            (false, 'c'),
+            (changes.size, 's'),
+            (changes.mtime, 't'),
+            (changes.perms, 'p'),
+            (changes.owner, 'o'),
+            (changes.group, 'g'),
+            (false, 'u'),
+            (changes.acl, 'a'),
+            (changes.xattr, 'x'),
+        ] {
+            s.push(if changed { c } else { '.' });
+        }
+    }
+    s
+}
+
+/// Write a path for line-based output, escaping control characters like
+/// rsync does ("\#012" for newline).
+fn write_path(out: &mut Vec<u8>, path: &Path) {
+    for &b in path.as_os_str().as_bytes() {
+        if b < 0x20 || b == 0x7f {
+            write!(out, "\\#{:03o}", b).unwrap();
+        } else {
+            out.push(b);
+        }
+    }
+}
+
+/// Writes one line per change made to the target, see --itemize and
+/// --out-format.
+pub struct Itemizer {
+    format: String,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Itemizer {
+    pub const DEFAULT_FORMAT: &'static str = "%i %n";
+
+    pub fn new(format: String, output: Box<dyn Write + Send>) -> Itemizer {
+        Itemizer {
+            format,
+            output: Mutex::new(output),
+        }
+    }
+
+    pub fn log(&self, path: &Path, file_type: FileType, action: Action, changes: &Changes, size: u64) {
+        let mut line = Vec::new();
+        let mut chars = self.format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                let mut buf = [0; 4];
+                line.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+            match chars.next() {
+                Some('i') => line.extend_from_slice(itemize_string(file_type, action, changes).as_bytes()),
+                Some('f') => line.push(if changes.flags { b'f' } else { b'.' }),
+                Some('n') => {
+                    write_path(&mut line, path);
+                    if file_type.is_dir() && action != Action::Deleted {
+                        line.push(b'/');
+                    }
+                }
+                Some('l') => write!(line, "{}", size).unwrap(),
+                Some('o') => line.extend_from_slice(match action {
+                    Action::Transferred if changes.created => b"created",
+                    Action::Transferred => b"data",
+                    Action::TypeChanged => b"type-changed",
+                    Action::Attributes => b"attrs",
+                    Action::Deleted => b"deleted",
+                }),
+                Some('%') => line.push(b'%'),
+                Some(other) => {
+                    line.push(b'%');
+                    let mut buf = [0; 4];
+                    line.extend_from_slice(other.encode_utf8(&mut buf).as_bytes());
+                }
+                None => line.push(b'%'),
+            }
+        }
+        line.push(b'\n');
+
+        let mut output = self.output.lock().unwrap();
+        if let Err(e) = output.write_all(&line) {
+            error!("Error writing itemized changes: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rsync_layout() {
+        let file = std::fs::metadata("Cargo.toml").unwrap().file_type();
+        let dir = std::fs::metadata(".").unwrap().file_type();
+        let changes = Changes {
+            size: true,
+            mtime: true,
+            flags: true,
+            ..Default::default()
+        };
+
+        assert_eq!(itemize_string(file, Action::Transferred, &changes), ">f.st......");
+        assert_eq!(itemize_string(file, Action::Transferred, &Changes::created()), ">f+++++++++");
+        assert_eq!(itemize_string(dir, Action::TypeChanged, &Changes::type_changed()), "cdT++++++++");
+        assert_eq!(itemize_string(dir, Action::Attributes, &Changes { flags: true, ..Default::default() }), ".d.........");
+        assert_eq!(itemize_string(file, Action::Deleted, &Changes::default()), "*deleting  ");
+    }
+}
@@ -1,20 +1,48 @@
+mod adaptive;
+mod control;
 mod copy;
 mod dir_scanner;
+mod error_log;
 mod file_copier;
+mod fileflags;
+mod itemize;
+mod json;
+mod metrics;
+mod pending;
+mod probe;
+mod progress;
+mod resources;
+mod retry;
+mod signals;
+mod space;
 mod stats;
+mod throttle;
+mod watchdog;
 
-use pretty_env_logger;
 use std::env::args_os;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+// Exit statuses, matching rsync's where they overlap
+const EXIT_OK: i32 = 0;
+const EXIT_ABORTED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NO_SPACE: i32 = 11;
+const EXIT_CANCELLED: i32 = 20;
+const EXIT_PARTIAL: i32 = 23;
+const EXIT_VANISHED: i32 = 24;
+const EXIT_STALLED: i32 = 30;
 
 fn parse_num_option<N: std::str::FromStr>(opt: Option<OsString>, flag: &'static str) -> N {
     let opt = match opt {
         Some(o) => o,
         None => {
             eprintln!("Missing value for {}", flag);
-            exit(2);
+            exit(EXIT_USAGE);
         }
     };
     if let Some(opt) = opt.to_str() {
@@ -22,8 +50,94 @@ fn parse_num_option<N: std::str::FromStr>(opt: Option<OsString>, flag: &'static
             return opt;
         }
     }
-    eprintln!("Invalid value for --entries");
-    exit(2);
+    eprintln!("Invalid value for {}", flag);
+    exit(EXIT_USAGE);
+}
+
+fn parse_size_option(opt: Option<OsString>, flag: &'static str) -> u64 {
+    let opt = parse_str_option(opt, flag);
+    match throttle::parse_size(&opt, 1024) {
+        Some(size) => size,
+        None => {
+            eprintln!("Invalid value for {}", flag);
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn parse_str_option(opt: Option<OsString>, flag: &'static str) -> String {
+    match opt.map(|o| o.into_string()) {
+        Some(Ok(o)) => o,
+        Some(Err(_)) => {
+            eprintln!("Invalid value for {}", flag);
+            exit(EXIT_USAGE);
+        }
+        None => {
+            eprintln!("Missing value for {}", flag);
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+/// Print the summary, write the metrics file, remove the control socket and
+/// exit.
+fn finish(stats: &stats::Stats, metrics_file: Option<&Path>, control_socket: Option<&Path>, status: i32) -> ! {
+    stats.print_summary();
+
+    if let Some(path) = metrics_file {
+        if let Err(e) = metrics::write_textfile(stats, path, Some(status)) {
+            eprintln!("Can't write metrics to {:?}: {}", path, e);
+        }
+    }
+
+    if let Some(path) = control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+
+    exit(status);
+}
+
+/// `fast-local-sync ctl SOCKET COMMAND...`, send a command to a running sync.
+fn ctl(mut args: impl Iterator<Item = OsString>) -> ! {
+    let socket = match args.next() {
+        Some(s) if s != "--help" => PathBuf::from(s),
+        _ => {
+            eprintln!(
+                "
+Usage: fast-local-sync ctl SOCKET COMMAND
+Commands:
+    pause
+        Stop starting new operations, and pause the copies in progress
+    resume
+        Continue after pause
+    cancel
+        Same as SIGINT: let the operations in progress finish and exit
+    threads scan|copy NUM
+        Change the number of scanning or copying threads
+    bwlimit LIMIT
+        Change the bandwidth limit, same syntax as --bwlimit
+    iops LIMIT
+        Change the limit of operations per second, same syntax as
+        --iops-limit
+    status
+        Show what each thread is doing"
+            );
+            exit(EXIT_USAGE);
+        }
+    };
+    let command: Vec<String> = args.map(|a| a.to_string_lossy().into_owned()).collect();
+    if command.is_empty() {
+        eprintln!("Missing command");
+        exit(EXIT_USAGE);
+    }
+    match control::send_command(&socket, &command.join(" ")) {
+        Ok(true) => exit(EXIT_OK),
+        Ok(false) => exit(EXIT_ABORTED),
+        Err(e) => {
+            eprintln!("Can't connect to {:?}: {}", socket, e);
+            exit(EXIT_ABORTED);
+        }
+    }
 }
 
 fn main() {
@@ -34,30 +148,217 @@ fn main() {
     let mut source = None;
     let mut target = None;
     let mut threads = None;
+    let mut scan_threads = None;
+    let mut copy_threads = None;
+    let mut adaptive = false;
     let mut print_stats = false;
+    let mut show_progress = false;
+    let mut modify_window = None;
+    let mut preserve_atime = true;
+    let mut noatime = false;
+    let mut xattr_filter = copy::XattrFilter::default();
+    let mut selinux = copy::SelinuxMode::Copy;
+    let mut fileflags = false;
+    let mut force_change = false;
+    let mut skip_nodump = false;
+    let mut error_log = None;
+    let mut retry_from = None;
+    let mut retry_policy = retry::RetryPolicy::default();
+    let mut op_timeout = None;
+    let mut stall_timeout = None;
+    let mut out_format = None;
+    let mut itemize_file = None;
+    let mut control_socket = None;
+    let mut bwlimit = None;
+    let mut iops_limit = None;
+    let mut ionice = None;
+    let mut nice = None;
+    let mut no_cache = false;
+    let mut direct_io_threshold = None;
+    let mut buffer_size = None;
+    let mut preallocate = true;
+    let mut space_check = space::SpaceCheck::Warn;
+    let mut full_policy = space::FullPolicy::default();
+
+    let mut metrics_job = "fast-local-sync".to_owned();
+    let mut metrics_file = None;
+    let mut metrics_file_interval = None;
 
     #[cfg(feature = "metrics")]
-    let mut metrics_port = None;
+    let mut metrics_listen = None;
 
-    let mut args = args_os();
+    let mut args = args_os().peekable();
     args.next();
+    if args.peek().is_some_and(|a| a == "ctl") {
+        args.next();
+        ctl(args);
+    }
     let usage = format!(
         "
 Usage: fast-local-sync [options] SOURCE DESTINATION
+       fast-local-sync ctl SOCKET COMMAND
 Options:
     --threads NUM_THREADS
         Set the number of threads used for scanning and copying files
+        (default is twice the number of CPUs available, taking the cgroup
+        CPU quota into account, between 2 and 8)
+    --scan-threads NUM_THREADS
+        Set the number of threads used for scanning, overriding --threads
+    --copy-threads NUM_THREADS
+        Set the number of threads used for copying, overriding --threads
+    --adaptive
+        Grow or shrink each pool of threads as the sync runs, depending on
+        the throughput and the length of its queue, starting from the
+        numbers above
     --print-stats
-        Regularly print the statistics to stdout{}
+        Regularly print the statistics to stdout
+    --progress
+        Show the progress, throughput, files being copied and an estimate
+        of the remaining time (printed every 10 seconds if stdout is not a
+        terminal)
+    --modify-window SECONDS
+        Consider modification times equal if they differ by less than this
+        (can be fractional, default is the target's timestamp precision)
+    --no-preserve-atime
+        Leave the access time of target entries alone instead of setting it
+        to the source's
+    --noatime
+        Open source files with O_NOATIME so reading them doesn't update
+        their access time (only works for files we own or as root)
+    --xattr-include PATTERN
+        Only copy extended attributes matching this pattern, for example
+        \"user.*\", with * and ? wildcards (can be repeated)
+    --xattr-exclude PATTERN
+        Don't copy extended attributes matching this pattern (can be
+        repeated). \"system.*\" is always excluded
+    --selinux copy|skip|relabel
+        What to do with SELinux labels: copy them from the source
+        (default), leave the target's alone, or set them to the label of
+        the parent directory on the target
+    --fileflags
+        Copy inode flags such as immutable, append-only, nodump (see
+        chattr(1))
+    --force-change
+        Temporarily clear immutable and append-only flags on target
+        entries that need to be replaced or deleted
+    --skip-nodump
+        Don't copy source entries that have the nodump flag
+    --error-log FILE
+        Write failures to FILE as JSON lines, with the relative path,
+        operation, errno and message
+    --retry-from FILE
+        Only sync the paths listed in FILE, an error log written by a
+        previous run with --error-log
+    --retries NUM
+        Retry operations that fail with a transient error up to NUM times
+        (default 0)
+    --retry-delay SECONDS
+        Delay before the first retry, doubled for each following one up to
+        a minute (default 1)
+    --retry-on ERRNO,...
+        Which errors are transient, by name or number (default
+        EIO,ESTALE,EAGAIN,ETIMEDOUT,EINTR)
+    --op-timeout SECONDS
+        Log operations that make no progress for this long, and count them
+        in the stalled workers metric
+    --stall-timeout SECONDS
+        Abort if no thread made any progress for this long
+    --bwlimit LIMIT
+        Limit the rate at which file contents are copied, in bytes per
+        second with an optional K, M, G suffix (powers of 1024). Can also
+        depend on the local time, for example \"08:00-20:00=10M,100M\"
+        for 10 MiB/s during the day and 100 MiB/s the rest of the time.
+        0 means unlimited
+    --iops-limit LIMIT
+        Limit the number of operations per second: metadata operations
+        while scanning and files opened for copying. Same syntax as
+        --bwlimit, with K meaning a thousand
+    --no-cache
+        Drop the files from the page cache as they are copied, so the
+        sync doesn't evict the cache of other programs. This also writes
+        the data to disk as it goes
+    --direct-io SIZE
+        Copy files at least this large with O_DIRECT, bypassing the page
+        cache entirely, for example 1G
+    --buffer-size SIZE
+        Size of the chunks files are copied in (default 128K)
+    --no-preallocate
+        Don't allocate the space for target files before copying them.
+        Preallocating avoids fragmentation and fails right away if there
+        is not enough space. It is never done for sparse files
+    --space-check warn|refuse|off
+        Compare the size of the files left to copy to the free space and
+        inodes on the target. With warn (default), this is done as the scan
+        goes, and files that replace existing ones count with their whole
+        size. With refuse, copying only starts once the whole tree has been
+        scanned, and the sync is aborted before writing any file if they
+        don't fit
+    --full-timeout SECONDS
+        When the target gets full, pause copying and wait this long for
+        space to free up, for example from the scanner deleting entries,
+        before aborting (default 60). Exceeding a disk quota is an error for
+        the file, not a reason to wait
+    --full-wait-deletions
+        Keep waiting for space while the scanner is still deleting
+        entries, only starting the timeout when it stops
+    --ionice CLASS[:LEVEL]
+        Set the I/O scheduling class (realtime, best-effort or idle) and
+        level (0 to 7, default 4) of all threads, like ionice(1)
+    --nice NICENESS
+        Set the CPU niceness of all threads, like nice(1)
+    --control SOCKET
+        Accept commands on this Unix socket, sent with
+        \"fast-local-sync ctl SOCKET COMMAND\" (run it with --help for the
+        list of commands): pause, resume, change the number of threads or
+        the bandwidth limit
+    --itemize
+        Print a line for each change made to the target, like rsync -i
+    --out-format FORMAT
+        Format of those lines, implies --itemize. %i is the change summary
+        like rsync's (e.g. \">f.st......\", and \"T++++++++\" for entries
+        replacing one of another type), %n the path, %l the size, %o the
+        action (created, type-changed, data, attrs, deleted), %f \"f\" if
+        the inode flags changed, \".\" otherwise. Default is \"%i %n\"
+    --itemize-file FILE
+        Write those lines to FILE instead of stdout
+    --metrics-file FILE
+        Write the statistics in Prometheus format to FILE at the end, for
+        node_exporter's textfile collector. Also includes the exit status
+        and the time of the last successful run
+    --metrics-file-interval SECONDS
+        Also rewrite that file periodically during the sync
+    --metrics-job NAME
+        Value of the job label of the metrics (default \"fast-local-sync\"),
+        source and target labels are also added{}
+Signals:
+    SIGINT, SIGTERM
+        Stop syncing new entries, wait for the copies in progress to finish
+        and exit. Send again to exit immediately
+    SIGUSR1
+        Print what each thread is doing and the queue lengths to stderr
 Environment variables:
     RUST_LOG
         Controls the logging level, for example \"info\"
-        or \"fast_local_sync::copy=debug\"",
+        or \"fast_local_sync::copy=debug\"
+Exit status:
+    0   Success
+    1   The sync could not start or was aborted
+    2   Invalid command line
+    11  Aborted because the target is full
+    20  Cancelled by SIGINT or SIGTERM
+    23  Partial transfer, some entries could not be synced
+    24  Partial transfer, some source entries vanished during the sync
+    30  Aborted because no progress was made for --stall-timeout",
         {
             #[cfg(feature = "metrics")]
             {"
     --metrics PORT
-        Expose the statistics in Prometheus format on HTTP PORT"}
+        Expose the statistics in Prometheus format on HTTP PORT, same as
+        --metrics-listen 0.0.0.0:PORT
+    --metrics-listen ADDR:PORT|unix:PATH
+        Expose the statistics on this address or Unix socket. /metrics has
+        the statistics, /status what each thread is doing as JSON, /healthz
+        and /ready can be used as probes"}
             #[cfg(not(feature = "metrics"))]
             {""}
         },
@@ -65,21 +366,202 @@ Environment variables:
     while let Some(arg) = args.next() {
         if &arg == "--help" {
             println!("{}", usage);
-            exit(0);
+            exit(EXIT_OK);
         } else if &arg == "--threads" {
             threads = Some(parse_num_option(args.next(), "--threads"));
+        } else if &arg == "--scan-threads" {
+            scan_threads = Some(parse_num_option(args.next(), "--scan-threads"));
+        } else if &arg == "--copy-threads" {
+            copy_threads = Some(parse_num_option(args.next(), "--copy-threads"));
+        } else if &arg == "--adaptive" {
+            adaptive = true;
         } else if &arg == "--metrics" {
             #[cfg(feature = "metrics")]
             {
-                metrics_port = Some(parse_num_option(args.next(), "--metrics"));
+                let port: u16 = parse_num_option(args.next(), "--metrics");
+                metrics_listen = Some(metrics::Listen::Tcp(([0, 0, 0, 0], port).into()));
             }
             #[cfg(not(feature = "metrics"))]
             {
                 eprintln!("Option --metrics was not compiled in");
-                exit(2);
+                exit(EXIT_USAGE);
             }
+        } else if &arg == "--metrics-listen" {
+            #[cfg(feature = "metrics")]
+            {
+                let value = parse_str_option(args.next(), "--metrics-listen");
+                metrics_listen = match metrics::Listen::parse(&value) {
+                    Some(l) => Some(l),
+                    None => {
+                        eprintln!("Invalid value for --metrics-listen");
+                        exit(EXIT_USAGE);
+                    }
+                };
+            }
+            #[cfg(not(feature = "metrics"))]
+            {
+                eprintln!("Option --metrics-listen was not compiled in");
+                exit(EXIT_USAGE);
+            }
+        } else if &arg == "--metrics-job" {
+            metrics_job = parse_str_option(args.next(), "--metrics-job");
+        } else if &arg == "--metrics-file" {
+            metrics_file = Some(PathBuf::from(args.next().unwrap_or_else(|| {
+                eprintln!("Missing value for --metrics-file");
+                exit(EXIT_USAGE);
+            })));
+        } else if &arg == "--metrics-file-interval" {
+            let seconds: f64 = parse_num_option(args.next(), "--metrics-file-interval");
+            if !seconds.is_finite() || seconds <= 0.0 {
+                eprintln!("Invalid value for --metrics-file-interval");
+                exit(EXIT_USAGE);
+            }
+            metrics_file_interval = Some(Duration::from_secs_f64(seconds));
         } else if &arg == "--print-stats" {
             print_stats = true;
+        } else if &arg == "--progress" {
+            show_progress = true;
+        } else if &arg == "--modify-window" {
+            let seconds: f64 = parse_num_option(args.next(), "--modify-window");
+            if !seconds.is_finite() || seconds < 0.0 {
+                eprintln!("Invalid value for --modify-window");
+                exit(EXIT_USAGE);
+            }
+            modify_window = Some(Duration::from_secs_f64(seconds));
+        } else if &arg == "--no-preserve-atime" {
+            preserve_atime = false;
+        } else if &arg == "--noatime" {
+            noatime = true;
+        } else if &arg == "--xattr-include" {
+            xattr_filter.include.push(parse_str_option(args.next(), "--xattr-include"));
+        } else if &arg == "--xattr-exclude" {
+            xattr_filter.exclude.push(parse_str_option(args.next(), "--xattr-exclude"));
+        } else if &arg == "--fileflags" {
+            fileflags = true;
+        } else if &arg == "--force-change" {
+            force_change = true;
+        } else if &arg == "--skip-nodump" {
+            skip_nodump = true;
+        } else if &arg == "--error-log" {
+            error_log = Some(PathBuf::from(args.next().unwrap_or_else(|| {
+                eprintln!("Missing value for --error-log");
+                exit(EXIT_USAGE);
+            })));
+        } else if &arg == "--retry-from" {
+            retry_from = Some(PathBuf::from(args.next().unwrap_or_else(|| {
+                eprintln!("Missing value for --retry-from");
+                exit(EXIT_USAGE);
+            })));
+        } else if &arg == "--retries" {
+            retry_policy.retries = parse_num_option(args.next(), "--retries");
+        } else if &arg == "--retry-delay" {
+            let seconds: f64 = parse_num_option(args.next(), "--retry-delay");
+            if !seconds.is_finite() || seconds < 0.0 {
+                eprintln!("Invalid value for --retry-delay");
+                exit(EXIT_USAGE);
+            }
+            retry_policy.initial_delay = Duration::from_secs_f64(seconds);
+        } else if &arg == "--retry-on" {
+            let list = parse_str_option(args.next(), "--retry-on");
+            retry_policy.errnos = list
+                .split(',')
+                .map(|name| retry::parse_errno(name.trim()).unwrap_or_else(|| {
+                    eprintln!("Unknown error {:?} in --retry-on", name);
+                    exit(EXIT_USAGE);
+                }))
+                .collect();
+        } else if &arg == "--op-timeout" {
+            let seconds: f64 = parse_num_option(args.next(), "--op-timeout");
+            if !seconds.is_finite() || seconds <= 0.0 {
+                eprintln!("Invalid value for --op-timeout");
+                exit(EXIT_USAGE);
+            }
+            op_timeout = Some(Duration::from_secs_f64(seconds));
+        } else if &arg == "--stall-timeout" {
+            let seconds: f64 = parse_num_option(args.next(), "--stall-timeout");
+            if !seconds.is_finite() || seconds <= 0.0 {
+                eprintln!("Invalid value for --stall-timeout");
+                exit(EXIT_USAGE);
+            }
+            stall_timeout = Some(Duration::from_secs_f64(seconds));
+        } else if &arg == "--bwlimit" {
+            let value = parse_str_option(args.next(), "--bwlimit");
+            bwlimit = Some(throttle::Schedule::parse(&value, 1024).unwrap_or_else(|| {
+                eprintln!("Invalid value for --bwlimit");
+                exit(EXIT_USAGE);
+            }));
+        } else if &arg == "--iops-limit" {
+            let value = parse_str_option(args.next(), "--iops-limit");
+            iops_limit = Some(throttle::Schedule::parse(&value, 1000).unwrap_or_else(|| {
+                eprintln!("Invalid value for --iops-limit");
+                exit(EXIT_USAGE);
+            }));
+        } else if &arg == "--no-cache" {
+            no_cache = true;
+        } else if &arg == "--direct-io" {
+            direct_io_threshold = Some(parse_size_option(args.next(), "--direct-io"));
+        } else if &arg == "--buffer-size" {
+            let size = parse_size_option(args.next(), "--buffer-size");
+            if size == 0 || size > 1 << 30 {
+                eprintln!("Invalid value for --buffer-size");
+                exit(EXIT_USAGE);
+            }
+            buffer_size = Some(size as usize);
+        } else if &arg == "--no-preallocate" {
+            preallocate = false;
+        } else if &arg == "--space-check" {
+            space_check = match parse_str_option(args.next(), "--space-check").as_str() {
+                "warn" => space::SpaceCheck::Warn,
+                "refuse" => space::SpaceCheck::Refuse,
+                "off" => space::SpaceCheck::Off,
+                _ => {
+                    eprintln!("Invalid value for --space-check");
+                    exit(EXIT_USAGE);
+                }
+            };
+        } else if &arg == "--full-timeout" {
+            let seconds: f64 = parse_num_option(args.next(), "--full-timeout");
+            if !seconds.is_finite() || seconds < 0.0 {
+                eprintln!("Invalid value for --full-timeout");
+                exit(EXIT_USAGE);
+            }
+            full_policy.timeout = Duration::from_secs_f64(seconds);
+        } else if &arg == "--full-wait-deletions" {
+            full_policy.wait_deletions = true;
+        } else if &arg == "--ionice" {
+            let value = parse_str_option(args.next(), "--ionice");
+            ionice = Some(resources::IoPriority::parse(&value).unwrap_or_else(|| {
+                eprintln!("Invalid value for --ionice");
+                exit(EXIT_USAGE);
+            }));
+        } else if &arg == "--nice" {
+            nice = Some(parse_num_option(args.next(), "--nice"));
+        } else if &arg == "--control" {
+            control_socket = Some(PathBuf::from(args.next().unwrap_or_else(|| {
+                eprintln!("Missing value for --control");
+                exit(EXIT_USAGE);
+            })));
+        } else if &arg == "--itemize" {
+            if out_format.is_none() {
+                out_format = Some(itemize::Itemizer::DEFAULT_FORMAT.to_owned());
+            }
+        } else if &arg == "--out-format" {
+            out_format = Some(parse_str_option(args.next(), "--out-format"));
+        } else if &arg == "--itemize-file" {
+            itemize_file = Some(PathBuf::from(args.next().unwrap_or_else(|| {
+                eprintln!("Missing value for --itemize-file");
+                exit(EXIT_USAGE);
+            })));
+        } else if &arg == "--selinux" {
+            selinux = match parse_str_option(args.next(), "--selinux").as_str() {
+                "copy" => copy::SelinuxMode::Copy,
+                "skip" => copy::SelinuxMode::Skip,
+                "relabel" => copy::SelinuxMode::Relabel,
+                _ => {
+                    eprintln!("Invalid value for --selinux");
+                    exit(EXIT_USAGE);
+                }
+            };
         } else {
 
             if source.is_none() {
@@ -89,18 +571,38 @@ Environment variables:
             } else {
                 eprintln!("Too many arguments");
                 eprintln!("{}", usage);
-                exit(2);
+                exit(EXIT_USAGE);
             }
         }
     }
 
-    let threads = threads.unwrap_or(8);
+    // This has to happen before any thread is started, they inherit it
+    if let Some(priority) = ionice {
+        if let Err(e) = resources::set_io_priority(priority) {
+            eprintln!("Can't set I/O priority: {}", e);
+            exit(EXIT_ABORTED);
+        }
+    }
+    if let Some(nice) = nice {
+        if let Err(e) = resources::set_nice(nice) {
+            eprintln!("Can't set niceness: {}", e);
+            exit(EXIT_ABORTED);
+        }
+    }
+
+    let threads = threads.unwrap_or_else(resources::default_threads);
+    let scan_threads = scan_threads.unwrap_or(threads);
+    let copy_threads = copy_threads.unwrap_or(threads);
+    if threads == 0 || scan_threads == 0 || copy_threads == 0 {
+        eprintln!("Number of threads can't be 0");
+        exit(EXIT_USAGE);
+    }
     let source: PathBuf = match source {
         Some(s) => s.into(),
         None => {
             eprintln!("Missing source");
             eprintln!("{}", usage);
-            exit(2);
+            exit(EXIT_USAGE);
         }
     };
     let target: PathBuf = match target {
@@ -108,44 +610,216 @@ Environment variables:
         None => {
             eprintln!("Missing target");
             eprintln!("{}", usage);
-            exit(2);
+            exit(EXIT_USAGE);
         }
     };
 
     if !target.exists() {
         eprintln!("Destination directory does not exist!");
-        exit(1);
+        exit(EXIT_ABORTED);
+    }
+
+    // Read the paths to retry now, in case the error log overwrites the file
+    let retry_paths = retry_from.map(|path| match error_log::read_paths(&path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Can't read {:?}: {}", path, e);
+            exit(EXIT_ABORTED);
+        }
+    });
+
+    // Find out what the target supports
+    let capabilities = probe::probe_target(&target);
+    info!("{}", capabilities);
+    if !capabilities.case_sensitive {
+        eprintln!("Warning: target is not case-sensitive, entries differing only by case will conflict");
+    }
+    let mut copy_options = copy::CopyOptions::from_capabilities(&capabilities);
+    if let Some(modify_window) = modify_window {
+        copy_options.modify_window = copy_options.modify_window.max(modify_window);
+    }
+    copy_options.preserve_atime = preserve_atime;
+    copy_options.noatime = noatime;
+    copy_options.xattr_filter = xattr_filter;
+    copy_options.selinux = selinux;
+    copy_options.fileflags = fileflags;
+    copy_options.force_change = force_change;
+    copy_options.skip_nodump = skip_nodump;
+    copy_options.no_cache = no_cache;
+    copy_options.direct_io_threshold = direct_io_threshold;
+    copy_options.preallocate = preallocate;
+    if let Some(buffer_size) = buffer_size {
+        copy_options.buffer_size = buffer_size;
     }
 
     // Initialize statistics
     let stats = stats::Stats::new();
+    let control = Arc::new(control::Control::new(stats.clone()));
+    if let Some(schedule) = bwlimit {
+        control.throttle.set_bandwidth(schedule);
+    }
+    if let Some(schedule) = iops_limit {
+        control.throttle.set_iops(schedule);
+    }
+    // Flags --force-change cleared on directories are put back even if the
+    // sync doesn't finish normally
+    let directory_flags = Arc::new(fileflags::DirectoryFlags::new(&source, &target, &copy_options));
+    let stats2 = stats.clone();
+    let directory_flags2 = directory_flags.clone();
+    signals::start(stats.clone(), control.clone(), EXIT_CANCELLED, move || directory_flags2.apply(&stats2));
+    control.start_schedule();
+    if let Some(path) = error_log {
+        match error_log::ErrorLog::create(&path) {
+            Ok(log) => stats.set_error_log(log),
+            Err(e) => {
+                eprintln!("Can't create error log {:?}: {}", path, e);
+                exit(EXIT_ABORTED);
+            }
+        }
+    }
+    if let Some(format) = out_format {
+        let output: Box<dyn std::io::Write + Send> = match itemize_file {
+            Some(path) => match std::fs::File::create(&path) {
+                Ok(f) => Box::new(std::io::LineWriter::new(f)),
+                Err(e) => {
+                    eprintln!("Can't create {:?}: {}", path, e);
+                    exit(EXIT_ABORTED);
+                }
+            },
+            None => Box::new(std::io::stdout()),
+        };
+        stats.set_itemizer(itemize::Itemizer::new(format, output));
+    } else if itemize_file.is_some() {
+        eprintln!("--itemize-file requires --itemize or --out-format");
+        exit(EXIT_USAGE);
+    }
     if print_stats {
         stats.start_print_loop();
     }
+    let progress = if show_progress {
+        Some(progress::Progress::start(stats.clone()))
+    } else {
+        None
+    };
+    stats.set_metric_labels(&[
+        ("job", &metrics_job),
+        ("source", &source.to_string_lossy()),
+        ("target", &target.to_string_lossy()),
+    ]);
     #[cfg(feature = "metrics")]
-    if let Some(port) = metrics_port {
-        stats.serve_prometheus(port);
+    if let Some(listen) = metrics_listen {
+        metrics::serve(stats.clone(), listen);
+    }
+    match (&metrics_file, metrics_file_interval) {
+        (Some(path), Some(interval)) => metrics::start_textfile_loop(stats.clone(), path.clone(), interval),
+        (None, Some(_)) => {
+            eprintln!("--metrics-file-interval requires --metrics-file");
+            exit(EXIT_USAGE);
+        }
+        _ => {}
+    }
+
+    if op_timeout.is_some() || stall_timeout.is_some() {
+        let stats2 = stats.clone();
+        let metrics_file = metrics_file.clone();
+        let control_socket = control_socket.clone();
+        let directory_flags = directory_flags.clone();
+        watchdog::start(stats.clone(), control.clone(), op_timeout, stall_timeout, move || {
+            directory_flags.apply(&stats2);
+            finish(&stats2, metrics_file.as_deref(), control_socket.as_deref(), EXIT_STALLED);
+        });
     }
 
     // Create worker pools
     let file_copy_pool = file_copier::FileCopyPool::new(
         source.as_path(),
         target.as_path(),
-        threads,
+        copy_threads,
+        copy_options.clone(),
+        retry_policy.clone(),
+        full_policy,
+        control.clone(),
         stats.clone(),
     );
     let dir_scan_pool = dir_scanner::DirScanPool::new(
         source.as_path(),
         target.as_path(),
-        threads,
+        scan_threads,
         file_copy_pool.clone(),
+        copy_options,
+        retry_policy,
+        directory_flags.clone(),
+        control.clone(),
         stats.clone(),
     );
 
+    if let Some(path) = &control_socket {
+        let controlled = control::Controlled {
+            control: control.clone(),
+            dir_scan_pool: dir_scan_pool.clone(),
+            file_copy_pool: file_copy_pool.clone(),
+            stats: stats.clone(),
+        };
+        if let Err(e) = control::serve(path, controlled) {
+            eprintln!("Can't listen on {:?}: {}", path, e);
+            exit(EXIT_ABORTED);
+        }
+    }
+
+    let adaptive = if adaptive {
+        Some(adaptive::Adaptive::start(stats.clone(), control.clone(), dir_scan_pool.clone(), file_copy_pool.clone()))
+    } else {
+        None
+    };
+    space::start_check(stats.clone(), target.clone(), space_check);
+    if space_check == space::SpaceCheck::Refuse {
+        control.pause_copies();
+    }
+
     // Enqueue work
-    dir_scan_pool.add("".into());
+    match retry_paths {
+        Some(paths) => {
+            for path in paths {
+                dir_scan_pool.add_entry(path);
+            }
+        }
+        None => dir_scan_pool.add("".into()),
+    }
+    stats.set_ready();
 
     // Wait until done
     dir_scan_pool.join();
+    stats.set_scan_complete();
+    if space_check == space::SpaceCheck::Refuse {
+        space::check_before_copying(&stats, &control, &target);
+    }
     file_copy_pool.join();
+    // Stop resizing first, it could start threads nobody would join
+    if let Some(adaptive) = adaptive {
+        adaptive.stop();
+    }
+    dir_scan_pool.shutdown();
+    file_copy_pool.shutdown();
+    directory_flags.apply(&stats);
+    stats.set_done();
+
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    // Like rsync, use a distinct status when the only problem was source
+    // files disappearing
+    let status = if stats.out_of_space() {
+        EXIT_NO_SPACE
+    } else if control.cancelled() {
+        EXIT_CANCELLED
+    } else if stats.errors() > 0 {
+        EXIT_PARTIAL
+    } else if stats.vanished_entries() > 0 {
+        EXIT_VANISHED
+    } else {
+        EXIT_OK
+    };
+
+    finish(&stats, metrics_file.as_deref(), control_socket.as_deref(), status);
 }
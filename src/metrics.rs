@@ -0,0 +1,270 @@
+use std::fmt::{Display, Write};
+use std::fs::File;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::stats::Stats;
+
+/// Upper bounds of the file size histogram buckets, in bytes
+pub const FILE_SIZE_BUCKETS: [f64; 9] = [
+    1024.0,
+    16384.0,
+    262144.0,
+    1048576.0,
+    16777216.0,
+    268435456.0,
+    1073741824.0,
+    17179869184.0,
+    274877906944.0,
+];
+
+/// Upper bounds of the copy latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 10.0, 60.0, 600.0];
+
+/// A Prometheus histogram, updated without locking.
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Number of observations in each bucket (not cumulative), plus one for
+    /// those above the last bound
+    buckets: Vec<AtomicU64>,
+    /// Sum of observations, as the bits of an f64
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|&b| value <= b).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+            Some((f64::from_bits(sum) + value).to_bits())
+        }).unwrap();
+    }
+
+    pub fn write(&self, out: &mut String, name: &str, help: &str, labels: &str) {
+        write!(out, "# HELP {} {}\n# TYPE {} histogram\n", name, help, name).unwrap();
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            writeln!(out, "{}_bucket{} {}", name, label_set(labels, &[("le", &le)]), count).unwrap();
+        }
+        writeln!(out, "{}_sum{} {}", name, label_set(labels, &[]), f64::from_bits(self.sum.load(Ordering::Relaxed))).unwrap();
+        writeln!(out, "{}_count{} {}", name, label_set(labels, &[]), count).unwrap();
+    }
+}
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Render labels as `name="value",...`, without the braces.
+pub fn format_labels(labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(name);
+        out.push_str("=\"");
+        escape_label(&mut out, value);
+        out.push('"');
+    }
+    out
+}
+
+/// Build `{...}` from the common labels and extra ones, or nothing if there
+/// are none.
+pub fn label_set(labels: &str, extra: &[(&str, &str)]) -> String {
+    let extra = format_labels(extra);
+    match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{{{}}}", labels),
+        (true, false) => format!("{{{}}}", extra),
+        (false, false) => format!("{{{},{}}}", labels, extra),
+    }
+}
+
+/// Write a metric that has a single value.
+pub fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, labels: &str, value: impl Display) {
+    write!(
+        out,
+        "# HELP {} {}\n# TYPE {} {}\n{}{} {}\n",
+        name, help, name, kind, name, label_set(labels, &[]), value,
+    ).unwrap();
+}
+
+/// Held while writing the textfile, so a periodic refresh doesn't replace
+/// the final one
+static TEXTFILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Read the last success time from a previous textfile, to carry it over.
+fn read_last_success(path: &Path) -> Option<f64> {
+    let content = std::fs::read_to_string(path).ok()?;
+    content
+        .lines()
+        .find(|l| l.starts_with("sync_last_success_time"))
+        .and_then(|l| l.rsplit(' ').next())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Write the statistics for node_exporter's textfile collector, see
+/// --metrics-file.
+///
+/// The file is written under a temporary name then renamed, so the
+/// collector never sees a partial file. `exit_status` is set for the final
+/// write, and when it is 0 the last success time is updated.
+pub fn write_textfile(stats: &Stats, path: &Path, exit_status: Option<i32>) -> std::io::Result<()> {
+    let _guard = TEXTFILE_LOCK.lock().unwrap();
+    if exit_status.is_none() && stats.done() {
+        return Ok(());
+    }
+
+    let labels = stats.metric_labels();
+    let mut out = stats.render_prometheus();
+    if let Some(status) = exit_status {
+        write_metric(&mut out, "sync_exit_status", "gauge", "Exit status of the sync.", labels, status);
+    }
+    let last_success = if exit_status == Some(0) {
+        Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64())
+    } else {
+        read_last_success(path)
+    };
+    if let Some(time) = last_success {
+        write_metric(
+            &mut out,
+            "sync_last_success_time",
+            "gauge",
+            "Time the last successful sync finished, in seconds since the epoch.",
+            labels,
+            time,
+        );
+    }
+
+    // node_exporter only reads *.prom files, so this is ignored
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = PathBuf::from(temp_path);
+    let result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(out.as_bytes())?;
+        file.sync_all()
+    }).and_then(|()| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Rewrite the textfile regularly until the sync is over.
+pub fn start_textfile_loop(stats: Arc<Stats>, path: PathBuf, interval: Duration) {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            if stats.done() {
+                return;
+            }
+            if let Err(e) = write_textfile(&stats, &path, None) {
+                error!("Error writing metrics to {:?}: {}", path, e);
+            }
+        }
+    });
+}
+
+/// Where to serve the metrics, see --metrics-listen.
+#[cfg(feature = "metrics")]
+pub enum Listen {
+    Tcp(std::net::SocketAddr),
+    Unix(std::path::PathBuf),
+}
+
+#[cfg(feature = "metrics")]
+impl Listen {
+    /// Parse "ADDR:PORT" or "unix:PATH".
+    pub fn parse(value: &str) -> Option<Listen> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            Some(Listen::Unix(path.into()))
+        } else {
+            value.parse().ok().map(Listen::Tcp)
+        }
+    }
+}
+
+/// Serve /metrics, /status, /healthz and /ready from a background thread.
+#[cfg(feature = "metrics")]
+pub fn serve(stats: Arc<Stats>, listen: Listen) {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::runtime::Builder;
+    use tracing::info;
+    use warp::Filter;
+    use warp::http::StatusCode;
+
+    std::thread::spawn(move || {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async move {
+            let metrics = {
+                let stats = stats.clone();
+                warp::path("metrics").map(move || stats.render_prometheus())
+            };
+            let status = {
+                let stats = stats.clone();
+                warp::path("status").map(move || {
+                    warp::reply::with_header(stats.status_json(), "Content-Type", "application/json")
+                })
+            };
+            let healthz = warp::path("healthz").map(|| "ok");
+            let ready = warp::path("ready").map(move || {
+                if stats.ready() {
+                    warp::reply::with_status("ready", StatusCode::OK)
+                } else {
+                    warp::reply::with_status("not ready", StatusCode::SERVICE_UNAVAILABLE)
+                }
+            });
+            let routes = metrics.or(status).or(healthz).or(ready);
+
+            match listen {
+                Listen::Tcp(addr) => {
+                    info!("Starting Prometheus HTTP server on {}", addr);
+                    warp::serve(routes).run(addr).await;
+                }
+                Listen::Unix(path) => {
+                    info!("Starting Prometheus HTTP server on {:?}", path);
+                    // Remove a socket left over by a previous run
+                    if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                        let _ = std::fs::remove_file(&path);
+                    }
+                    let listener = match tokio::net::UnixListener::bind(&path) {
+                        Ok(l) => l,
+                        Err(e) => {
+                            error!("Can't listen on {:?}: {}", path, e);
+                            return;
+                        }
+                    };
+                    let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+                    warp::serve(routes).run_incoming(incoming).await;
+                }
+            }
+        });
+    });
+}
@@ -0,0 +1,40 @@
+use std::sync::{Condvar, Mutex};
+
+/// Number of work items queued or in progress in a pool, that can be waited
+/// on until it drops to zero.
+///
+/// Workers have to add the items they produce before marking their own as
+/// done, so the count can't reach zero while there is still work coming.
+pub struct Pending {
+    count: Mutex<usize>,
+    zero: Condvar,
+}
+
+impl Pending {
+    pub fn new() -> Pending {
+        Pending {
+            count: Mutex::new(0),
+            zero: Condvar::new(),
+        }
+    }
+
+    pub fn add(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    pub fn done(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.zero.notify_all();
+        }
+    }
+
+    /// Block until there is no work left.
+    pub fn wait(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.zero.wait(count).unwrap();
+        }
+    }
+}
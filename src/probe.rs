@@ -0,0 +1,241 @@
+use filetime::{FileTime, set_file_times};
+use std::fmt;
+use std::fs::{File, Permissions, remove_file, set_permissions, symlink_metadata};
+use std::os::unix::fs::{MetadataExt, PermissionsExt, lchown, symlink};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// What the target filesystem supports, found by trying each operation
+/// on a scratch file in the target root.
+pub struct Capabilities {
+    pub ownership: bool,
+    pub permissions: bool,
+    pub symlinks: bool,
+    pub acl: bool,
+    pub xattr: bool,
+    pub timestamp_granularity: Duration,
+    pub case_sensitive: bool,
+}
+
+impl Capabilities {
+    /// Assume everything works, used when the target can't be probed.
+    fn all() -> Capabilities {
+        Capabilities {
+            ownership: true,
+            permissions: true,
+            symlinks: true,
+            acl: cfg!(feature = "acl"),
+            xattr: cfg!(feature = "attr"),
+            timestamp_granularity: Duration::ZERO,
+            case_sensitive: true,
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn yes_no(b: bool) -> &'static str {
+            if b { "yes" } else { "no" }
+        }
+
+        write!(
+            f,
+            "Target supports: ownership={} permissions={} symlinks={} acl={} xattr={} timestamps={} case-sensitive={}",
+            yes_no(self.ownership),
+            yes_no(self.permissions),
+            yes_no(self.symlinks),
+            yes_no(self.acl),
+            yes_no(self.xattr),
+            if self.timestamp_granularity.is_zero() {
+                "1ns".to_owned()
+            } else {
+                format!("{:?}", self.timestamp_granularity)
+            },
+            yes_no(self.case_sensitive),
+        )
+    }
+}
+
+// Removes the probe files when probing is done, even on early return
+struct ProbeFiles(Vec<PathBuf>);
+
+impl Drop for ProbeFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// Work out the timestamp granularity from a time written and what was read
+/// back, zero if it was kept to the nanosecond.
+///
+/// The time read back is a multiple of the granularity, which can be larger
+/// than the difference: exFAT stores `.123456789` as `.12`, a difference of
+/// 3.5ms but a granularity of 10ms.
+fn timestamp_granularity(written: FileTime, read: FileTime) -> Duration {
+    if read == written {
+        return Duration::ZERO;
+    }
+
+    let to_duration = |t: FileTime| Duration::new(t.unix_seconds().max(0) as u64, t.nanoseconds());
+    let (written, read) = (to_duration(written), to_duration(read));
+    let difference = written.abs_diff(read);
+
+    let step = if read.subsec_nanos() != 0 {
+        let mut step = 1;
+        while read.subsec_nanos() % (step * 10) == 0 {
+            step *= 10;
+        }
+        Duration::from_nanos(step as u64)
+    } else if read.as_secs() % 2 == 0 {
+        // We wrote an odd number of seconds
+        Duration::from_secs(2)
+    } else {
+        Duration::from_secs(1)
+    };
+
+    difference.max(step)
+}
+
+pub fn probe_target(target: &Path) -> Capabilities {
+    let name = format!(".fast-local-sync-probe-{}", std::process::id());
+    let path = target.join(&name);
+    let mut files = ProbeFiles(Vec::new());
+
+    if let Err(e) = File::create(&path) {
+        warn!("Can't create probe file in target, assuming all operations are supported: {}", e);
+        return Capabilities::all();
+    }
+    files.0.push(path.clone());
+
+    let mut caps = Capabilities::all();
+
+    // Ownership; only root can give files away, so only probe that case.
+    // Otherwise we can only chown to ourselves, which works everywhere
+    caps.ownership = if unsafe { libc::geteuid() } == 0 {
+        match lchown(&path, Some(1), Some(1)) {
+            Ok(()) => match symlink_metadata(&path) {
+                Ok(m) => m.uid() == 1 && m.gid() == 1,
+                Err(_) => false,
+            },
+            Err(e) => {
+                debug!("probe lchown: {}", e);
+                false
+            }
+        }
+    } else {
+        true
+    };
+
+    // Permissions, some filesystems accept chmod but ignore it
+    caps.permissions = match set_permissions(&path, Permissions::from_mode(0o604)) {
+        Ok(()) => match symlink_metadata(&path) {
+            Ok(m) => m.mode() & 0o7777 == 0o604,
+            Err(_) => false,
+        },
+        Err(e) => {
+            debug!("probe chmod: {}", e);
+            false
+        }
+    };
+
+    // Symbolic links
+    let link_path = target.join(format!("{}-link", name));
+    caps.symlinks = match symlink(&name, &link_path) {
+        Ok(()) => {
+            files.0.push(link_path);
+            true
+        }
+        Err(e) => {
+            debug!("probe symlink: {}", e);
+            false
+        }
+    };
+
+    // ACLs
+    #[cfg(feature = "acl")]
+    {
+        use exacl::{AclOption, getfacl, setfacl};
+
+        caps.acl = match getfacl(&path, Some(AclOption::ACCESS_ACL))
+            .and_then(|acl| setfacl(&[&path], &acl, Some(AclOption::ACCESS_ACL)))
+        {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("probe setfacl: {}", e);
+                false
+            }
+        };
+    }
+
+    // Extended attributes
+    #[cfg(feature = "attr")]
+    {
+        caps.xattr = match xattr::set(&path, "user.fast-local-sync.probe", b"1")
+            .and_then(|()| xattr::get(&path, "user.fast-local-sync.probe"))
+        {
+            Ok(value) => value.as_deref() == Some(&b"1"[..]),
+            Err(e) => {
+                debug!("probe xattr: {}", e);
+                false
+            }
+        };
+    }
+
+    // Timestamp granularity, by setting a time with an odd number of
+    // seconds and nanoseconds and seeing what we get back
+    let time = FileTime::from_unix_time(1_000_000_001, 123_456_789);
+    caps.timestamp_granularity = match set_file_times(&path, time, time)
+        .and_then(|()| symlink_metadata(&path))
+    {
+        Ok(m) => timestamp_granularity(time, FileTime::from_last_modification_time(&m)),
+        Err(e) => {
+            debug!("probe set_file_times: {}", e);
+            Duration::from_secs(2)
+        }
+    };
+
+    // Case sensitivity
+    caps.case_sensitive = symlink_metadata(target.join(name.to_uppercase())).is_err();
+
+    caps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn granularity(seconds: i64, nanoseconds: u32) -> Duration {
+        let written = FileTime::from_unix_time(1_000_000_001, 123_456_789);
+        timestamp_granularity(written, FileTime::from_unix_time(seconds, nanoseconds))
+    }
+
+    #[test]
+    fn nanoseconds() {
+        assert_eq!(granularity(1_000_000_001, 123_456_789), Duration::ZERO);
+    }
+
+    #[test]
+    fn ntfs() {
+        assert_eq!(granularity(1_000_000_001, 123_456_700), Duration::from_nanos(100));
+    }
+
+    #[test]
+    fn exfat() {
+        assert_eq!(granularity(1_000_000_001, 120_000_000), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(granularity(1_000_000_001, 0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn fat() {
+        // Rounded down or up to an even number of seconds
+        assert_eq!(granularity(1_000_000_000, 0), Duration::from_secs(2));
+        assert_eq!(granularity(1_000_000_002, 0), Duration::from_secs(2));
+    }
+}
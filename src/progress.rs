@@ -0,0 +1,166 @@
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, bounded};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::stats::{Stats, format_bytes, format_duration};
+
+/// Time constant of the smoothed rates
+const SMOOTHING: Duration = Duration::from_secs(5);
+
+/// Live progress display, see --progress.
+pub struct Progress {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Progress {
+    /// Start displaying progress. If stdout is a terminal, it is redrawn in
+    /// place every second, otherwise a line is printed every 10 seconds.
+    pub fn start(stats: Arc<Stats>) -> Progress {
+        let tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        let (stop, stop_recv) = bounded(1);
+        let thread = std::thread::spawn(move || progress_thread(&stats, tty, stop_recv));
+        Progress { stop, thread }
+    }
+
+    /// Draw the final state and stop.
+    pub fn finish(self) {
+        drop(self.stop);
+        self.thread.join().unwrap();
+    }
+}
+
+/// Exponentially-weighted moving averages of the copy rates.
+struct Rates {
+    bytes: f64,
+    files: f64,
+    last_time: Instant,
+    last_bytes: u64,
+    last_files: usize,
+}
+
+impl Rates {
+    fn new(stats: &Stats) -> Rates {
+        Rates {
+            bytes: 0.0,
+            files: 0.0,
+            last_time: Instant::now(),
+            last_bytes: stats.copied_bytes(),
+            last_files: stats.copied_entries(),
+        }
+    }
+
+    fn update(&mut self, stats: &Stats) {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last_time).as_secs_f64();
+        if dt <= 0.0 {
+            return;
+        }
+        let (bytes, files) = (stats.copied_bytes(), stats.copied_entries());
+        let alpha = 1.0 - (-dt / SMOOTHING.as_secs_f64()).exp();
+        self.bytes += alpha * ((bytes - self.last_bytes) as f64 / dt - self.bytes);
+        self.files += alpha * ((files - self.last_files) as f64 / dt - self.files);
+        self.last_time = now;
+        self.last_bytes = bytes;
+        self.last_files = files;
+    }
+}
+
+fn status_line(stats: &Stats, rates: &Rates) -> String {
+    let copied_bytes = stats.copied_bytes();
+    let queued_bytes = stats.queued_copy_bytes();
+
+    let mut line = format!(
+        "Scanned {}, copied {}/{} files, {}/{}",
+        stats.scanned_entries(),
+        stats.copied_entries(),
+        stats.queued_copy_entries(),
+        format_bytes(copied_bytes),
+        format_bytes(queued_bytes),
+    );
+    if queued_bytes > 0 {
+        write!(line, " ({:.0}%)", copied_bytes as f64 * 100.0 / queued_bytes as f64).unwrap();
+    }
+    write!(line, ", {}/s, {:.0} files/s", format_bytes(rates.bytes as u64), rates.files).unwrap();
+
+    // The total is only known once scanning is done
+    if !stats.scan_complete() {
+        line.push_str(", scanning");
+    } else if rates.bytes >= 1.0 {
+        let eta = Duration::from_secs_f64(stats.remaining_copy_bytes() as f64 / rates.bytes);
+        write!(line, ", ETA {}", format_duration(eta)).unwrap();
+    }
+    write!(line, ", elapsed {}", format_duration(stats.elapsed())).unwrap();
+    line
+}
+
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if ret == 0 && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+fn progress_thread(stats: &Stats, tty: bool, stop: Receiver<()>) {
+    let interval = if tty { Duration::from_secs(1) } else { Duration::from_secs(10) };
+    let mut rates = Rates::new(stats);
+    let mut drawn_lines = 0;
+
+    loop {
+        let done = !matches!(stop.recv_timeout(interval), Err(RecvTimeoutError::Timeout));
+        rates.update(stats);
+
+        let mut out = String::new();
+        if !tty {
+            out.push_str(&status_line(stats, &rates));
+            out.push('\n');
+        } else {
+            let mut lines = vec![status_line(stats, &rates)];
+            if !done {
+                for worker in stats.worker_activity() {
+                    if let Some(path) = worker.path {
+                        lines.push(format!(
+                            "  {} {} ({}, {})",
+                            worker.kind,
+                            path.display(),
+                            format_bytes(worker.size),
+                            format_duration(worker.since.elapsed()),
+                        ));
+                    }
+                }
+            }
+
+            // Go back to the start of what we drew last time and clear it.
+            // Lines are cut to the terminal width so they don't wrap
+            let width = terminal_width().saturating_sub(1);
+            if drawn_lines > 0 {
+                write!(out, "\x1b[{}A", drawn_lines).unwrap();
+            }
+            out.push_str("\r\x1b[J");
+            for line in &lines {
+                out.extend(line.chars().take(width));
+                out.push('\n');
+            }
+            drawn_lines = lines.len();
+        }
+
+        // Stdout may be closed, e.g. when piped into head. Stop drawing then,
+        // the sync itself goes on
+        let mut stdout = std::io::stdout().lock();
+        if let Err(error) = stdout.write_all(out.as_bytes()).and_then(|()| stdout.flush()) {
+            debug!("Can't write progress, stopping it: {}", error);
+            return;
+        }
+
+        if done {
+            return;
+        }
+    }
+}
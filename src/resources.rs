@@ -0,0 +1,63 @@
+// From linux/ioprio.h
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// I/O scheduling class and level, see ionice(1) and --ionice.
+#[derive(Clone, Copy)]
+pub struct IoPriority {
+    class: u32,
+    level: u32,
+}
+
+impl IoPriority {
+    /// Parse `CLASS[:LEVEL]`, the class being realtime, best-effort or idle
+    /// (or 1, 2, 3 like ionice), the level from 0 (highest) to 7.
+    pub fn parse(s: &str) -> Option<IoPriority> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level.parse().ok()?)),
+            None => (s, None),
+        };
+        let class = match class {
+            "realtime" | "rt" | "1" => 1,
+            "best-effort" | "be" | "2" => 2,
+            "idle" | "3" => 3,
+            _ => return None,
+        };
+        let level = level.unwrap_or(4);
+        if level > 7 {
+            return None;
+        }
+        Some(IoPriority { class, level })
+    }
+}
+
+/// Set the I/O priority of the calling thread. Threads it starts afterwards
+/// inherit it.
+pub fn set_io_priority(priority: IoPriority) -> std::io::Result<()> {
+    let value = (priority.class << IOPRIO_CLASS_SHIFT) | priority.level;
+    let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the CPU niceness of the calling thread. Like the I/O priority, on
+/// Linux this is per-thread and inherited by the threads it starts
+/// afterwards.
+pub fn set_nice(nice: i32) -> std::io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Default number of threads of each pool, if --threads is not given.
+///
+/// Syncing is mostly waiting on I/O, so this is twice the number of CPUs we
+/// can use, between 2 and 8. `available_parallelism()` already takes the
+/// affinity mask and the cgroup CPU quota into account.
+pub fn default_threads() -> usize {
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    (cpus * 2).clamp(2, 8)
+}
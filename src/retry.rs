@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::stats::{Stats, Worker};
+
+/// Error numbers retried by default, that NFS and FUSE filesystems return
+/// sporadically.
+const DEFAULT_ERRNOS: [i32; 5] = [libc::EIO, libc::ESTALE, libc::EAGAIN, libc::ETIMEDOUT, libc::EINTR];
+
+const ERRNO_NAMES: [(&str, i32); 10] = [
+    ("EIO", libc::EIO),
+    ("ESTALE", libc::ESTALE),
+    ("EAGAIN", libc::EAGAIN),
+    ("ETIMEDOUT", libc::ETIMEDOUT),
+    ("EINTR", libc::EINTR),
+    ("EBUSY", libc::EBUSY),
+    ("ENOLINK", libc::ENOLINK),
+    ("ECONNRESET", libc::ECONNRESET),
+    ("EHOSTDOWN", libc::EHOSTDOWN),
+    ("EREMOTEIO", libc::EREMOTEIO),
+];
+
+/// Parse an error name like "EIO" or a number.
+pub fn parse_errno(name: &str) -> Option<i32> {
+    ERRNO_NAMES
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, errno)| *errno)
+        .or_else(|| name.parse().ok())
+}
+
+/// How to retry operations that failed with a transient error.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// How many times to retry, 0 to never retry
+    pub retries: u32,
+    /// Delay before the first retry, doubled each time
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Which errors are worth retrying
+    pub errnos: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries: 0,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            errnos: DEFAULT_ERRNOS.to_vec(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_transient(&self, error: &std::io::Error) -> bool {
+        match error.raw_os_error() {
+            Some(errno) => self.errnos.contains(&errno),
+            None => false,
+        }
+    }
+
+    /// Run an operation, retrying it with exponential backoff on transient
+    /// errors. `worker` is the thread running it, the backoff doesn't count
+    /// as a stall.
+    pub fn run<T, F: FnMut() -> std::io::Result<T>>(
+        &self,
+        path: &Path,
+        stats: &Stats,
+        worker: &Worker,
+        mut operation: F,
+    ) -> std::io::Result<T> {
+        let mut attempt = 0;
+        let mut delay = self.initial_delay;
+        loop {
+            match operation() {
+                Ok(r) => {
+                    if attempt > 0 {
+                        stats.add_retry_recovered(1);
+                    }
+                    return Ok(r);
+                }
+                Err(e) if attempt < self.retries && self.is_transient(&e) => {
+                    attempt += 1;
+                    warn!(
+                        "Transient error on {:?}: {}, retrying in {:?} ({}/{})",
+                        path, e, delay, attempt, self.retries,
+                    );
+                    stats.add_retried_operations(1);
+                    worker.sleep(delay);
+                    delay = (delay * 2).min(self.max_delay);
+                }
+                Err(e) => {
+                    if attempt > 0 {
+                        stats.add_retry_exhausted(1);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+use tracing::error;
+
+use crate::control::Control;
+use crate::stats::Stats;
+
+/// Handle signals from a dedicated thread.
+///
+/// The signals are blocked in the calling thread, and in the threads it
+/// starts afterwards, so this has to be called before starting any other
+/// thread. SIGUSR1 prints what each worker is doing to stderr. The first
+/// SIGINT or SIGTERM cancels the sync, the second one calls `on_abort` and
+/// exits immediately with `abort_status`.
+pub fn start(stats: Arc<Stats>, control: Arc<Control>, abort_status: i32, on_abort: impl FnOnce() + Send + 'static) {
+    let set = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        if ret != 0 {
+            error!("Can't block signals: {}", std::io::Error::from_raw_os_error(ret));
+            return;
+        }
+        set
+    };
+
+    std::thread::spawn(move || {
+        let mut on_abort = Some(on_abort);
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+                continue;
+            }
+            if signal == libc::SIGUSR1 {
+                eprint!("{}", stats.status_report());
+            } else if !control.cancelled() {
+                eprintln!("Cancelling, waiting for operations in progress to finish (interrupt again to abort)");
+                control.cancel();
+            } else {
+                eprintln!("Aborting");
+                if let Some(on_abort) = on_abort.take() {
+                    on_abort();
+                }
+                std::process::exit(abort_status);
+            }
+        }
+    });
+}
@@ -0,0 +1,206 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::control::Control;
+use crate::stats::{Stats, format_bytes, format_duration};
+
+/// How often the free space is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Space available to us on a filesystem.
+pub struct FreeSpace {
+    pub bytes: u64,
+    /// None if the filesystem has no limit on the number of inodes
+    pub inodes: Option<u64>,
+}
+
+pub fn free_space(path: &Path) -> std::io::Result<FreeSpace> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(FreeSpace {
+        bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        inodes: if stat.f_files == 0 { None } else { Some(stat.f_favail as u64) },
+    })
+}
+
+/// Whether an error means the target is full. Exceeding a quota (EDQUOT)
+/// doesn't: statvfs() doesn't show it, and there is nothing to wait for.
+pub fn is_out_of_space(error: &std::io::Error) -> bool {
+    // Not comparing the errno, errors with more context keep the kind
+    error.kind() == std::io::ErrorKind::StorageFull
+}
+
+/// How many times an operation is tried again after waiting for space
+pub const MAX_SPACE_RETRIES: u32 = 3;
+
+/// What to do when the files left to copy don't fit on the target, see
+/// --space-check.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SpaceCheck {
+    Off,
+    Warn,
+    Refuse,
+}
+
+/// Why copying `bytes` in `files` new files doesn't fit in `free`, if it
+/// doesn't.
+fn shortage(bytes: u64, files: usize, free: &FreeSpace) -> Option<String> {
+    if bytes > free.bytes {
+        Some(format!("{} left to copy but only {} free on the target", format_bytes(bytes), format_bytes(free.bytes)))
+    } else if free.inodes.is_some_and(|inodes| files as u64 > inodes) {
+        Some(format!("{} files left to copy but only {} free inodes on the target", files, free.inodes.unwrap()))
+    } else {
+        None
+    }
+}
+
+/// With --space-check warn, compare what is left to copy to the free space
+/// on the target as the scan discovers it, and warn once if it doesn't fit.
+///
+/// The whole size of files that replace existing ones is counted, so this
+/// can overestimate the space needed.
+pub fn start_check(stats: Arc<Stats>, target: PathBuf, mode: SpaceCheck) {
+    if mode != SpaceCheck::Warn {
+        return;
+    }
+    std::thread::spawn(move || {
+        while !stats.done() {
+            std::thread::sleep(CHECK_INTERVAL);
+            let free = match free_space(&target) {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Can't get the free space of {:?}: {}", target, e);
+                    return;
+                }
+            };
+            let files = stats.copy_queue_depth() + stats.copies_in_flight();
+            if let Some(message) = shortage(stats.remaining_copy_bytes(), files, &free) {
+                eprintln!("Warning: {}", message);
+                return;
+            }
+        }
+    });
+}
+
+/// With --space-check refuse, the copies are paused until the scan is over.
+/// Then what they need, minus the size of the files they replace, is compared
+/// to the free space on the target once, before anything is written. If it
+/// doesn't fit the sync is cancelled. Resumes the copies either way.
+pub fn check_before_copying(stats: &Stats, control: &Control, target: &Path) {
+    let bytes = stats.remaining_copy_bytes().saturating_sub(stats.replaced_bytes());
+    let files = stats.copy_queue_depth().saturating_sub(stats.replaced_entries());
+    match free_space(target) {
+        Ok(free) => {
+            if let Some(message) = shortage(bytes, files, &free) {
+                error!("{}, aborting", message);
+                stats.set_out_of_space();
+                control.cancel();
+            }
+        }
+        Err(e) => error!("Can't get the free space of {:?}: {}", target, e),
+    }
+    control.resume_copies();
+}
+
+/// What to do when a copy fails because the target is full.
+#[derive(Clone)]
+pub struct FullPolicy {
+    /// How long to wait for space to free up
+    pub timeout: Duration,
+    /// Keep waiting as long as the scanner deletes entries
+    pub wait_deletions: bool,
+}
+
+impl Default for FullPolicy {
+    fn default() -> FullPolicy {
+        FullPolicy {
+            timeout: Duration::from_secs(60),
+            wait_deletions: false,
+        }
+    }
+}
+
+/// Pauses the copies when the target gets full, rather than failing every
+/// one of them.
+pub struct OutOfSpace {
+    policy: FullPolicy,
+    /// Held by the thread waiting for space, the others wait behind it
+    waiting: Mutex<()>,
+    gave_up: AtomicBool,
+}
+
+impl OutOfSpace {
+    pub fn new(policy: FullPolicy) -> OutOfSpace {
+        OutOfSpace {
+            policy,
+            waiting: Mutex::new(()),
+            gave_up: AtomicBool::new(false),
+        }
+    }
+
+    /// Called after a copy, or creating a directory, failed with ENOSPC.
+    /// Pauses the copies and waits until `needed` bytes are free on the
+    /// target, meanwhile the scanners go on deleting extraneous entries.
+    /// Returns whether to try again, if not the sync is cancelled.
+    ///
+    /// Space only counts as freed up if there is more than when the operation
+    /// failed: ENOSPC can happen while statvfs() still shows free space, e.g.
+    /// when btrfs runs out of metadata space.
+    pub fn wait_for_space(&self, target: &Path, needed: u64, control: &Control, stats: &Stats) -> bool {
+        let at_failure = free_space(target).ok();
+        let _waiting = self.waiting.lock().unwrap();
+        if self.gave_up.load(Ordering::Relaxed) || control.cancelled() {
+            return false;
+        }
+        let has_space = || match (free_space(target), &at_failure) {
+            (Ok(free), Some(at_failure)) => {
+                let grown = free.bytes > at_failure.bytes || free.inodes > at_failure.inodes;
+                grown && free.bytes > needed && free.inodes != Some(0)
+            }
+            _ => false,
+        };
+        // Another thread might have waited already
+        if has_space() {
+            return true;
+        }
+
+        warn!("Target is full, pausing copies until {} is free", format_bytes(needed));
+        control.pause_copies();
+        let mut deadline = Instant::now() + self.policy.timeout;
+        let mut removed = stats.removed_entries();
+        let result = loop {
+            if control.cancelled() {
+                break false;
+            }
+            if has_space() {
+                info!("Space freed up on the target, resuming copies");
+                break true;
+            }
+            if self.policy.wait_deletions && !stats.scan_complete() {
+                let now_removed = stats.removed_entries();
+                if now_removed != removed {
+                    removed = now_removed;
+                    deadline = Instant::now() + self.policy.timeout;
+                }
+            }
+            if Instant::now() >= deadline {
+                error!("Target still full after {}, aborting", format_duration(self.policy.timeout));
+                self.gave_up.store(true, Ordering::Relaxed);
+                stats.set_out_of_space();
+                control.cancel();
+                break false;
+            }
+            std::thread::sleep(CHECK_INTERVAL);
+        };
+        control.resume_copies();
+        result
+    }
+}
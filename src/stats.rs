@@ -1,30 +1,207 @@
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::fs::FileType;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::error_log::{ErrorLog, Operation, errno};
+use crate::itemize::{Action, Changes, Itemizer};
+#[cfg(feature = "metrics")]
+use crate::json;
+use crate::metrics::{FILE_SIZE_BUCKETS, Histogram, LATENCY_BUCKETS, format_labels, label_set, write_metric};
+
+/// Format a size in bytes with binary units, e.g. "12.3 MiB".
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Format a duration for humans, e.g. "1h02m03s".
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// What a worker thread is currently doing.
+#[derive(Clone)]
+pub struct WorkerActivity {
+    pub id: usize,
+    pub kind: &'static str,
+    /// The entry being processed, None if idle
+    pub path: Option<PathBuf>,
+    pub operation: Option<Operation>,
+    /// Size of that entry
+    pub size: u64,
+    /// How much of it was processed so far
+    pub done_bytes: u64,
+    /// When it started processing it, or became idle
+    pub since: Instant,
+    /// When it started processing it or last wrote data
+    pub last_progress: Instant,
+}
+
+/// Longest sleep of a worker between two reports to the watchdog
+const SLEEP_SLICE: Duration = Duration::from_millis(500);
+
+/// Handle a worker thread uses to report what it's doing.
+pub struct Worker(Arc<Mutex<WorkerActivity>>);
+
+impl Worker {
+    pub fn busy(&self, path: &Path, operation: Operation, size: u64) {
+        let mut activity = self.0.lock().unwrap();
+        activity.path = Some(path.to_owned());
+        activity.operation = Some(operation);
+        activity.size = size;
+        activity.done_bytes = 0;
+        activity.since = Instant::now();
+        activity.last_progress = activity.since;
+    }
+
+    /// Record that the current operation made progress.
+    pub fn advance(&self, bytes: u64) {
+        let mut activity = self.0.lock().unwrap();
+        activity.done_bytes += bytes;
+        activity.last_progress = Instant::now();
+    }
+
+    /// Sleep on purpose during the current operation, e.g. because of
+    /// --bwlimit or a retry backoff. Sleeps in slices, refreshing the last
+    /// progress after each one, so the watchdog doesn't take it for a hang.
+    pub fn sleep(&self, mut duration: Duration) {
+        while !duration.is_zero() {
+            let slice = duration.min(SLEEP_SLICE);
+            thread::sleep(slice);
+            self.0.lock().unwrap().last_progress = Instant::now();
+            duration -= slice;
+        }
+    }
+
+    pub fn idle(&self) {
+        let mut activity = self.0.lock().unwrap();
+        activity.path = None;
+        activity.operation = None;
+        activity.size = 0;
+        activity.done_bytes = 0;
+        activity.since = Instant::now();
+        activity.last_progress = activity.since;
+    }
+}
 
 pub struct Stats {
+    start_time: Instant,
+    start_system_time: SystemTime,
     scanned_entries: AtomicUsize,
     skipped_entries: AtomicUsize,
+    updated_metadata_entries: AtomicUsize,
     queued_copy_entries: AtomicUsize,
+    queued_copy_bytes: AtomicU64,
+    scan_queue_depth: AtomicUsize,
+    copy_queue_depth: AtomicUsize,
+    copies_in_flight: AtomicUsize,
+    /// Current size of the pools, see --adaptive
+    scan_threads: AtomicUsize,
+    copy_threads: AtomicUsize,
+    stalled_workers: AtomicUsize,
+    /// Size of the files queued or being copied
+    remaining_copy_bytes: AtomicU64,
+    /// Queued files that replace a target file, and the size of those
+    replaced_entries: AtomicUsize,
+    replaced_bytes: AtomicU64,
     copied_entries: AtomicUsize,
     copied_bytes: AtomicU64,
     removed_entries: AtomicUsize,
     removed_bytes: AtomicU64,
+    vanished_entries: AtomicUsize,
     errors: AtomicUsize,
+    retried_operations: AtomicUsize,
+    retry_recovered: AtomicUsize,
+    retry_exhausted: AtomicUsize,
+    errors_by_type: Mutex<BTreeMap<(&'static str, Option<i32>), usize>>,
+    file_sizes: Histogram,
+    copy_latency: Histogram,
+    scan_complete: AtomicBool,
+    /// The sync was aborted because the target is full
+    out_of_space: AtomicBool,
+    /// Current --bwlimit and --iops-limit, 0 for unlimited
+    bwlimit: AtomicU64,
+    iops_limit: AtomicU64,
+    /// Time threads spent waiting because of those limits, in microseconds
+    throttled_time: AtomicU64,
+    /// Activity of the running threads, dropped when they exit
+    workers: Mutex<Vec<Weak<Mutex<WorkerActivity>>>>,
+    next_worker_id: AtomicUsize,
+    ready: AtomicBool,
+    done: AtomicBool,
+    /// Labels added to every metric, already formatted
+    metric_labels: OnceLock<String>,
+    error_log: OnceLock<ErrorLog>,
+    itemizer: OnceLock<Itemizer>,
 }
 
 impl Stats {
     pub fn new() -> Arc<Stats> {
         Arc::new(Stats {
+            start_time: Instant::now(),
+            start_system_time: SystemTime::now(),
             scanned_entries: AtomicUsize::new(0),
             skipped_entries: AtomicUsize::new(0),
+            updated_metadata_entries: AtomicUsize::new(0),
             queued_copy_entries: AtomicUsize::new(0),
+            queued_copy_bytes: AtomicU64::new(0),
+            scan_queue_depth: AtomicUsize::new(0),
+            copy_queue_depth: AtomicUsize::new(0),
+            copies_in_flight: AtomicUsize::new(0),
+            scan_threads: AtomicUsize::new(0),
+            copy_threads: AtomicUsize::new(0),
+            stalled_workers: AtomicUsize::new(0),
+            remaining_copy_bytes: AtomicU64::new(0),
+            replaced_entries: AtomicUsize::new(0),
+            replaced_bytes: AtomicU64::new(0),
             copied_entries: AtomicUsize::new(0),
             copied_bytes: AtomicU64::new(0),
             removed_entries: AtomicUsize::new(0),
             removed_bytes: AtomicU64::new(0),
+            vanished_entries: AtomicUsize::new(0),
             errors: AtomicUsize::new(0),
+            retried_operations: AtomicUsize::new(0),
+            retry_recovered: AtomicUsize::new(0),
+            retry_exhausted: AtomicUsize::new(0),
+            errors_by_type: Mutex::new(BTreeMap::new()),
+            file_sizes: Histogram::new(&FILE_SIZE_BUCKETS),
+            copy_latency: Histogram::new(&LATENCY_BUCKETS),
+            scan_complete: AtomicBool::new(false),
+            out_of_space: AtomicBool::new(false),
+            bwlimit: AtomicU64::new(0),
+            iops_limit: AtomicU64::new(0),
+            throttled_time: AtomicU64::new(0),
+            workers: Mutex::new(Vec::new()),
+            next_worker_id: AtomicUsize::new(0),
+            ready: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+            metric_labels: OnceLock::new(),
+            error_log: OnceLock::new(),
+            itemizer: OnceLock::new(),
         })
 
     }
@@ -37,94 +214,66 @@ impl Stats {
         });
     }
 
-    #[cfg(feature = "metrics")]
-    pub fn serve_prometheus(self: &Arc<Self>, port: u16) {
-        use tokio::runtime::Builder;
-        use tracing::info;
-        use warp::Filter;
+    /// Render the statistics in Prometheus text format.
+    pub fn render_prometheus(&self) -> String {
+        let labels = self.metric_labels();
+        let mut out = String::new();
+        let metrics: [(&str, &str, &str, u64); 18] = [
+            ("sync_scanned_entries", "counter", "Total number of entries scanned.", self.scanned_entries.load(Ordering::Relaxed) as u64),
+            ("sync_skipped_entries", "counter", "Total number of entries skipped because they were up-to-date.", self.skipped_entries.load(Ordering::Relaxed) as u64),
+            ("sync_updated_metadata_entries", "counter", "Total number of entries whose content was up-to-date but had their ACLs or extended attributes updated.", self.updated_metadata_entries.load(Ordering::Relaxed) as u64),
+            ("sync_queued_copy_entries", "counter", "Total number of entries added to the queue for copy.", self.queued_copy_entries.load(Ordering::Relaxed) as u64),
+            ("sync_queued_copy_bytes", "counter", "Total size of files added to the queue for copy.", self.queued_copy_bytes.load(Ordering::Relaxed)),
+            ("sync_scan_queue_depth", "gauge", "Number of directories waiting to be scanned.", self.scan_queue_depth.load(Ordering::Relaxed) as u64),
+            ("sync_copy_queue_depth", "gauge", "Number of files waiting to be copied.", self.copy_queue_depth.load(Ordering::Relaxed) as u64),
+            ("sync_copies_in_flight", "gauge", "Number of files being copied.", self.copies_in_flight.load(Ordering::Relaxed) as u64),
+            ("sync_remaining_bytes", "gauge", "Size of the files waiting to be copied or being copied.", self.remaining_copy_bytes.load(Ordering::Relaxed)),
+            ("sync_copied_entries", "counter", "Total number of files copied.", self.copied_entries.load(Ordering::Relaxed) as u64),
+            ("sync_copied_bytes", "counter", "Total size of files copied.", self.copied_bytes.load(Ordering::Relaxed)),
+            ("sync_removed_entries", "counter", "Total number of entries deleted.", self.removed_entries.load(Ordering::Relaxed) as u64),
+            ("sync_removed_bytes", "counter", "Total size of files deleted.", self.removed_bytes.load(Ordering::Relaxed)),
+            ("sync_vanished_entries", "counter", "Total number of source entries that disappeared while syncing.", self.vanished_entries.load(Ordering::Relaxed) as u64),
+            ("sync_errors", "counter", "Total number of errors during this sync operation.", self.errors.load(Ordering::Relaxed) as u64),
+            ("sync_retried_operations", "counter", "Total number of retries of operations that failed with a transient error.", self.retried_operations.load(Ordering::Relaxed) as u64),
+            ("sync_retry_recovered", "counter", "Total number of operations that succeeded after being retried.", self.retry_recovered.load(Ordering::Relaxed) as u64),
+            ("sync_retry_exhausted", "counter", "Total number of operations that still failed after being retried.", self.retry_exhausted.load(Ordering::Relaxed) as u64),
+        ];
+        for (name, kind, help, value) in metrics {
+            write_metric(&mut out, name, kind, help, labels, value);
+        }
+        write_metric(
+            &mut out,
+            "sync_stalled_workers",
+            "gauge",
+            "Number of threads stuck in an operation for longer than --op-timeout.",
+            labels,
+            self.stalled_workers.load(Ordering::Relaxed),
+        );
+        write_metric(&mut out, "sync_scan_threads", "gauge", "Number of scanning threads.", labels, self.scan_threads.load(Ordering::Relaxed));
+        write_metric(&mut out, "sync_copy_threads", "gauge", "Number of copying threads.", labels, self.copy_threads.load(Ordering::Relaxed));
+        write_metric(&mut out, "sync_bwlimit_bytes", "gauge", "Current bandwidth limit in bytes per second, 0 if unlimited.", labels, self.bwlimit.load(Ordering::Relaxed));
+        write_metric(&mut out, "sync_iops_limit", "gauge", "Current limit of operations per second, 0 if unlimited.", labels, self.iops_limit.load(Ordering::Relaxed));
+        write_metric(&mut out, "sync_throttled_seconds", "counter", "Total time threads spent waiting because of the bandwidth or operations limit.", labels, self.throttled_time().as_secs_f64());
 
-        let stats = self.clone();
+        out.push_str(
+            "# HELP sync_operation_errors Number of errors by operation and error number.\n\
+            # TYPE sync_operation_errors counter\n",
+        );
+        for ((operation, errno), count) in self.errors_by_type.lock().unwrap().iter() {
+            let errno = errno.map(|e| e.to_string()).unwrap_or_else(|| "none".to_owned());
+            let labels = label_set(labels, &[("operation", operation), ("errno", &errno)]);
+            out.push_str(&format!("sync_operation_errors{} {}\n", labels, count));
+        }
 
-        std::thread::spawn(move || {
-            info!("Starting Prometheus HTTP server on port {}", port);
-
-            let rt = Builder::new_current_thread().enable_all().build().unwrap();
-            rt.block_on(async move {
-                let addr: std::net::SocketAddr = ([0, 0, 0, 0], port).into();
-                let routes = warp::path("metrics").map(move || {
-                    use std::io::Write;
-
-                    let mut buffer = vec![];
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_scanned_entries Total number of entries scanned.\n\
-                        # TYPE sync_scanned_entries counter\n\
-                        sync_scanned_entries {}\n",
-                        stats.scanned_entries.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_skipped_entries Total number of entries skipped because they were up-to-date.\n\
-                        # TYPE sync_skipped_entries counter\n\
-                        sync_skipped_entries {}\n",
-                        stats.skipped_entries.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_queued_copy_entries Total number of entries added to the queue for copy.\n\
-                        # TYPE sync_queued_copy_entries counter\n\
-                        sync_queued_copy_entries {}\n",
-                        stats.queued_copy_entries.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_copied_entries Total number of files copied.\n\
-                        # TYPE sync_copied_entries counter\n\
-                        sync_copied_entries {}\n",
-                        stats.copied_entries.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_copied_bytes Total size of files copied.\n\
-                        # TYPE sync_copied_bytes counter\n\
-                        sync_copied_bytes {}\n",
-                        stats.copied_bytes.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_removed_entries Total number of entries deleted.\n\
-                        # TYPE sync_removed_entries counter\n\
-                        sync_removed_entries {}\n",
-                        stats.removed_entries.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_removed_bytes Total size of files deleted.\n\
-                        # TYPE sync_removed_bytes counter\n\
-                        sync_removed_bytes {}\n",
-                        stats.removed_bytes.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    write!(
-                        &mut buffer,
-                        "# HELP sync_errors Total number of errors during this sync operation.\n\
-                        # TYPE sync_errors counter\n\
-                        sync_errors {}\n",
-                        stats.errors.load(Ordering::Relaxed),
-                    ).unwrap();
-
-                    buffer
-                });
-                warp::serve(routes).run(addr).await;
-            });
-        });
+        self.file_sizes.write(&mut out, "sync_file_size_bytes", "Size of the files copied.", labels);
+        self.copy_latency.write(&mut out, "sync_copy_duration_seconds", "Time taken to copy each file.", labels);
+
+        let start_time = self.start_system_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write_metric(&mut out, "sync_start_time", "gauge", "Time the sync started, in seconds since the epoch.", labels, start_time.as_secs_f64());
+        write_metric(&mut out, "sync_duration_seconds", "gauge", "Time the sync has been running for.", labels, self.start_time.elapsed().as_secs_f64());
+        write_metric(&mut out, "sync_done", "gauge", "Whether the sync is over.", labels, self.done.load(Ordering::Relaxed) as u8);
+
+        out
     }
 
     fn print_thread(&self) {
@@ -138,25 +287,74 @@ impl Stats {
                 println!(
                     "SCANNED     \
                      SKIPPED     \
+                     METADATA    \
                      QUEUED      \
                      COPIED      \
                      REMOVED     \
-                     ERRORS"
+                     VANISHED    \
+                     ERRORS      \
+                     SCAN-QUEUE  \
+                     COPY-QUEUE  \
+                     IN-FLIGHT   \
+                     REMAINING"
                 );
             }
             i += 1;
             println!(
-                "{:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+                "{:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
                 self.scanned_entries.load(Ordering::Relaxed),
                 self.skipped_entries.load(Ordering::Relaxed),
+                self.updated_metadata_entries.load(Ordering::Relaxed),
                 self.queued_copy_entries.load(Ordering::Relaxed),
                 self.copied_entries.load(Ordering::Relaxed),
                 self.removed_entries.load(Ordering::Relaxed),
+                self.vanished_entries.load(Ordering::Relaxed),
                 self.errors.load(Ordering::Relaxed),
+                self.scan_queue_depth.load(Ordering::Relaxed),
+                self.copy_queue_depth.load(Ordering::Relaxed),
+                self.copies_in_flight.load(Ordering::Relaxed),
+                format_bytes(self.remaining_copy_bytes.load(Ordering::Relaxed)),
             )
         }
     }
 
+    /// Print the totals, once the sync is over. Stdout being closed isn't
+    /// an error, the exit status still tells how the sync went.
+    pub fn print_summary(&self) {
+        let elapsed = self.start_time.elapsed();
+        let copied_bytes = self.copied_bytes.load(Ordering::Relaxed);
+        let mut out = String::new();
+        writeln!(
+            out,
+            "Scanned {} entries, skipped {}, updated metadata of {}, copied {} ({}), removed {} ({})",
+            self.scanned_entries.load(Ordering::Relaxed),
+            self.skipped_entries.load(Ordering::Relaxed),
+            self.updated_metadata_entries.load(Ordering::Relaxed),
+            self.copied_entries.load(Ordering::Relaxed),
+            format_bytes(copied_bytes),
+            self.removed_entries.load(Ordering::Relaxed),
+            format_bytes(self.removed_bytes.load(Ordering::Relaxed)),
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{} errors, {} vanished, {} retries ({} recovered, {} gave up), elapsed {:.1}s, {}/s",
+            self.errors.load(Ordering::Relaxed),
+            self.vanished_entries.load(Ordering::Relaxed),
+            self.retried_operations.load(Ordering::Relaxed),
+            self.retry_recovered.load(Ordering::Relaxed),
+            self.retry_exhausted.load(Ordering::Relaxed),
+            elapsed.as_secs_f64(),
+            format_bytes((copied_bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64),
+        )
+        .unwrap();
+        let throttled_time = self.throttled_time();
+        if !throttled_time.is_zero() {
+            writeln!(out, "Threads waited {} in total because of the limits", format_duration(throttled_time)).unwrap();
+        }
+        let _ = std::io::stdout().lock().write_all(out.as_bytes());
+    }
+
     pub fn add_scanned_entries(&self, count: usize) {
         self.scanned_entries.fetch_add(count, Ordering::Relaxed);
     }
@@ -165,8 +363,69 @@ impl Stats {
         self.skipped_entries.fetch_add(count, Ordering::Relaxed);
     }
 
-    pub fn add_queued_copy_entries(&self, count: usize) {
+    pub fn add_updated_metadata_entries(&self, count: usize) {
+        self.updated_metadata_entries.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_queued_copy(&self, count: usize, bytes: u64) {
         self.queued_copy_entries.fetch_add(count, Ordering::Relaxed);
+        self.copy_queue_depth.fetch_add(count, Ordering::Relaxed);
+        if bytes != 0 {
+            self.queued_copy_bytes.fetch_add(bytes, Ordering::Relaxed);
+            self.remaining_copy_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// A file was taken from the copy queue.
+    pub fn copy_started(&self) {
+        self.copy_queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.copies_in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A file is done being copied, successfully or not. `queued_bytes` is
+    /// the size it was queued with.
+    pub fn copy_finished(&self, queued_bytes: u64) {
+        self.copies_in_flight.fetch_sub(1, Ordering::Relaxed);
+        if queued_bytes != 0 {
+            self.remaining_copy_bytes.fetch_sub(queued_bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_scan_threads(&self, count: usize) {
+        self.scan_threads.store(count, Ordering::Relaxed);
+    }
+
+    pub fn set_copy_threads(&self, count: usize) {
+        self.copy_threads.store(count, Ordering::Relaxed);
+    }
+
+    /// The limits changed, see --bwlimit and --iops-limit.
+    pub fn set_throttle_limits(&self, bwlimit: u64, iops_limit: u64) {
+        self.bwlimit.store(bwlimit, Ordering::Relaxed);
+        self.iops_limit.store(iops_limit, Ordering::Relaxed);
+    }
+
+    /// A thread waited because of the limits.
+    pub fn add_throttled_time(&self, duration: Duration) {
+        self.throttled_time.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn throttled_time(&self) -> Duration {
+        Duration::from_micros(self.throttled_time.load(Ordering::Relaxed))
+    }
+
+    pub fn set_stalled_workers(&self, count: usize) {
+        self.stalled_workers.store(count, Ordering::Relaxed);
+    }
+
+    /// A directory or entry was added to the scan queue.
+    pub fn scan_queued(&self) {
+        self.scan_queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A directory or entry was taken from the scan queue.
+    pub fn scan_started(&self) {
+        self.scan_queue_depth.fetch_sub(1, Ordering::Relaxed);
     }
 
     pub fn add_copied(&self, count: usize, bytes: u64) {
@@ -176,6 +435,18 @@ impl Stats {
         }
     }
 
+    /// Record the size of a copied file and how long copying it took.
+    pub fn observe_copy(&self, bytes: u64, duration: Duration) {
+        self.file_sizes.observe(bytes as f64);
+        self.copy_latency.observe(duration.as_secs_f64());
+    }
+
+    /// A queued file replaces a target file of `bytes`, see --space-check.
+    pub fn add_replaced(&self, bytes: u64) {
+        self.replaced_entries.fetch_add(1, Ordering::Relaxed);
+        self.replaced_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
     pub fn add_removed(&self, count: usize, bytes: u64) {
         self.removed_entries.fetch_add(count, Ordering::Relaxed);
         if bytes != 0 {
@@ -183,7 +454,269 @@ impl Stats {
         }
     }
 
-    pub fn add_errors(&self, count: usize) {
-        self.errors.fetch_add(count, Ordering::Relaxed);
+    pub fn add_vanished_entries(&self, count: usize) {
+        self.vanished_entries.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Also write errors to this log, see --error-log.
+    pub fn set_error_log(&self, error_log: ErrorLog) {
+        if self.error_log.set(error_log).is_err() {
+            panic!("Error log already set");
+        }
+    }
+
+    /// Write changes to this output, see --itemize.
+    pub fn set_itemizer(&self, itemizer: Itemizer) {
+        if self.itemizer.set(itemizer).is_err() {
+            panic!("Itemizer already set");
+        }
+    }
+
+    /// Record a change made to the target, if itemized output is enabled.
+    pub fn itemize(&self, path: &Path, file_type: FileType, action: Action, changes: &Changes, size: u64) {
+        if let Some(itemizer) = self.itemizer.get() {
+            itemizer.log(path, file_type, action, changes, size);
+        }
+    }
+
+    /// Log and count a failure.
+    pub fn record_error(&self, path: &Path, operation: Operation, error: &std::io::Error) {
+        error!("Error {} {:?}: {}", operation.description(), path, error);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        *self.errors_by_type.lock().unwrap().entry((operation.name(), errno(error))).or_insert(0) += 1;
+        if let Some(error_log) = self.error_log.get() {
+            error_log.write(path, operation, error);
+        }
+    }
+
+    pub fn add_retried_operations(&self, count: usize) {
+        self.retried_operations.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_retry_recovered(&self, count: usize) {
+        self.retry_recovered.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_retry_exhausted(&self, count: usize) {
+        self.retry_exhausted.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Set labels added to every metric, such as job, source and target.
+    pub fn set_metric_labels(&self, labels: &[(&str, &str)]) {
+        if self.metric_labels.set(format_labels(labels)).is_err() {
+            panic!("Metric labels already set");
+        }
+    }
+
+    pub fn metric_labels(&self) -> &str {
+        self.metric_labels.get().map(|l| l.as_str()).unwrap_or("")
+    }
+
+    /// The sync has started, see /ready.
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn set_out_of_space(&self) {
+        self.out_of_space.store(true, Ordering::Relaxed);
+    }
+
+    pub fn out_of_space(&self) -> bool {
+        self.out_of_space.load(Ordering::Relaxed)
+    }
+
+    /// The sync is over, see sync_done.
+    pub fn set_done(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+
+    pub fn done(&self) -> bool {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Mark the scan as finished, after which the queued totals are final.
+    pub fn set_scan_complete(&self) {
+        self.scan_complete.store(true, Ordering::Relaxed);
+    }
+
+    /// Add a worker thread, for the status and progress displays.
+    pub fn register_worker(&self, kind: &'static str) -> Worker {
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|w| w.strong_count() > 0);
+        let activity = Arc::new(Mutex::new(WorkerActivity {
+            id: self.next_worker_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            path: None,
+            operation: None,
+            size: 0,
+            done_bytes: 0,
+            since: Instant::now(),
+            last_progress: Instant::now(),
+        }));
+        workers.push(Arc::downgrade(&activity));
+        Worker(activity)
+    }
+
+    pub fn worker_activity(&self) -> Vec<WorkerActivity> {
+        let workers = self.workers.lock().unwrap();
+        workers.iter().filter_map(|w| w.upgrade()).map(|w| w.lock().unwrap().clone()).collect()
+    }
+
+    /// What each worker is doing, as JSON, see /status.
+    #[cfg(feature = "metrics")]
+    pub fn status_json(&self) -> String {
+        let mut out = String::new();
+        write!(
+            out,
+            "{{\"elapsed\": {}, \"scan_queue\": {}, \"copy_queue\": {}, \"copies_in_flight\": {}, \"scan_threads\": {}, \"copy_threads\": {}, \"bwlimit\": {}, \"iops_limit\": {}, \"throttled_seconds\": {}, \"workers\": [",
+            self.start_time.elapsed().as_secs_f64(),
+            self.scan_queue_depth.load(Ordering::Relaxed),
+            self.copy_queue_depth.load(Ordering::Relaxed),
+            self.copies_in_flight.load(Ordering::Relaxed),
+            self.scan_threads.load(Ordering::Relaxed),
+            self.copy_threads.load(Ordering::Relaxed),
+            self.bwlimit.load(Ordering::Relaxed),
+            self.iops_limit.load(Ordering::Relaxed),
+            self.throttled_time().as_secs_f64(),
+        ).unwrap();
+        for (i, worker) in self.worker_activity().iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            write!(out, "{{\"id\": {}, \"kind\": ", worker.id).unwrap();
+            json::write_string(&mut out, worker.kind);
+            out.push_str(", \"path\": ");
+            match &worker.path {
+                Some(path) => json::write_string(&mut out, &path.to_string_lossy()),
+                None => out.push_str("null"),
+            }
+            out.push_str(", \"operation\": ");
+            match worker.operation {
+                Some(operation) => json::write_string(&mut out, operation.name()),
+                None => out.push_str("null"),
+            }
+            write!(
+                out,
+                ", \"size\": {}, \"done_bytes\": {}, \"seconds\": {}, \"seconds_since_progress\": {}}}",
+                worker.size,
+                worker.done_bytes,
+                worker.since.elapsed().as_secs_f64(),
+                worker.last_progress.elapsed().as_secs_f64(),
+            ).unwrap();
+        }
+        out.push_str("]}");
+        out
+    }
+
+    /// What each worker is doing, for humans, see SIGUSR1.
+    pub fn status_report(&self) -> String {
+        let mut out = format!(
+            "Status after {}: scan queue {}, copy queue {}, {} copies in flight, {} scan threads, {} copy threads\n",
+            format_duration(self.start_time.elapsed()),
+            self.scan_queue_depth.load(Ordering::Relaxed),
+            self.copy_queue_depth.load(Ordering::Relaxed),
+            self.copies_in_flight.load(Ordering::Relaxed),
+            self.scan_threads.load(Ordering::Relaxed),
+            self.copy_threads.load(Ordering::Relaxed),
+        );
+        let bwlimit = self.bwlimit.load(Ordering::Relaxed);
+        let iops_limit = self.iops_limit.load(Ordering::Relaxed);
+        if bwlimit != 0 || iops_limit != 0 {
+            writeln!(
+                out,
+                "Limited to {}/s and {} operations/s (0 is unlimited), threads waited {}",
+                format_bytes(bwlimit),
+                iops_limit,
+                format_duration(self.throttled_time()),
+            ).unwrap();
+        }
+        for worker in self.worker_activity() {
+            match (&worker.path, worker.operation) {
+                (Some(path), Some(operation)) => {
+                    write!(out, "  {} {}: {} {:?}", worker.kind, worker.id, operation.description(), path).unwrap();
+                    if worker.size > 0 {
+                        write!(out, " ({}/{})", format_bytes(worker.done_bytes), format_bytes(worker.size)).unwrap();
+                    }
+                    writeln!(out, " for {}", format_duration(worker.since.elapsed())).unwrap();
+                }
+                _ => writeln!(
+                    out,
+                    "  {} {}: idle for {}",
+                    worker.kind,
+                    worker.id,
+                    format_duration(worker.since.elapsed()),
+                ).unwrap(),
+            }
+        }
+        out
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    pub fn scanned_entries(&self) -> usize {
+        self.scanned_entries.load(Ordering::Relaxed)
+    }
+
+    pub fn scan_queue_depth(&self) -> usize {
+        self.scan_queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn copy_queue_depth(&self) -> usize {
+        self.copy_queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn copies_in_flight(&self) -> usize {
+        self.copies_in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn removed_entries(&self) -> usize {
+        self.removed_entries.load(Ordering::Relaxed)
+    }
+
+    pub fn scan_complete(&self) -> bool {
+        self.scan_complete.load(Ordering::Relaxed)
+    }
+
+    pub fn queued_copy_entries(&self) -> usize {
+        self.queued_copy_entries.load(Ordering::Relaxed)
+    }
+
+    pub fn queued_copy_bytes(&self) -> u64 {
+        self.queued_copy_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn remaining_copy_bytes(&self) -> u64 {
+        self.remaining_copy_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn replaced_entries(&self) -> usize {
+        self.replaced_entries.load(Ordering::Relaxed)
+    }
+
+    pub fn replaced_bytes(&self) -> u64 {
+        self.replaced_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn copied_entries(&self) -> usize {
+        self.copied_entries.load(Ordering::Relaxed)
+    }
+
+    pub fn copied_bytes(&self) -> u64 {
+        self.copied_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn vanished_entries(&self) -> usize {
+        self.vanished_entries.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
     }
 }
@@ -0,0 +1,300 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::stats::{Stats, Worker, format_bytes};
+
+/// Parse a size or rate such as `10M` or `1.5k`. Suffixes are powers of
+/// `base`, 1024 for bytes and 1000 for operations. Sizes that don't fit in
+/// 64 bits are rejected.
+pub fn parse_size(s: &str, base: u64) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let unit = unit.to_ascii_lowercase();
+    let unit = unit.strip_suffix('b').unwrap_or(&unit);
+    let unit = unit.strip_suffix('i').unwrap_or(unit);
+    let exponent = match unit {
+        "" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        _ => return None,
+    };
+    let size = number * base.pow(exponent) as f64;
+    if !size.is_finite() || size < 0.0 || size >= u64::MAX as f64 {
+        return None;
+    }
+    Some(size as u64)
+}
+
+/// Parse `HH:MM` into minutes since midnight.
+fn parse_time_of_day(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes != 0) {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+/// Minutes since midnight, local time.
+fn local_time_of_day() -> u32 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_hour * 60 + tm.tm_min) as u32
+    }
+}
+
+/// A rate limit that can depend on the time of day, for example
+/// `08:00-20:00=10M,0` for 10M during the day and no limit at night.
+#[derive(Clone, Default)]
+pub struct Schedule {
+    /// Limit outside of the windows, 0 for unlimited
+    default: u64,
+    /// Start and end in minutes since midnight, and the limit in that
+    /// window. The first matching window applies, windows can go past
+    /// midnight
+    windows: Vec<(u32, u32, u64)>,
+}
+
+impl Schedule {
+    /// Parse a comma-separated list of `HH:MM-HH:MM=RATE` windows, and
+    /// optionally a `RATE` for the rest of the time. Rates are bytes if
+    /// `base` is 1024, operations if it's 1000.
+    pub fn parse(s: &str, base: u64) -> Option<Schedule> {
+        let mut schedule = Schedule::default();
+        let mut has_default = false;
+        for item in s.split(',') {
+            match item.split_once('=') {
+                Some((window, rate)) => {
+                    let (start, end) = window.split_once('-')?;
+                    schedule.windows.push((
+                        parse_time_of_day(start.trim())?,
+                        parse_time_of_day(end.trim())?,
+                        parse_size(rate, base)?,
+                    ));
+                }
+                None => {
+                    if has_default {
+                        return None;
+                    }
+                    has_default = true;
+                    schedule.default = parse_size(item, base)?;
+                }
+            }
+        }
+        Some(schedule)
+    }
+
+    /// The limit at this time, in minutes since midnight.
+    fn rate_at(&self, time: u32) -> u64 {
+        for &(start, end, rate) in &self.windows {
+            let inside = if start <= end {
+                start <= time && time < end
+            } else {
+                time >= start || time < end
+            };
+            if inside {
+                return rate;
+            }
+        }
+        self.default
+    }
+}
+
+/// Token bucket, allowing bursts of up to a second's worth.
+struct TokenBucket {
+    /// Tokens per second, 0 for unlimited
+    rate: AtomicU64,
+    /// Tokens that can be taken right away, and when that was computed
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new() -> TokenBucket {
+        TokenBucket {
+            rate: AtomicU64::new(0),
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Take tokens, returning how long to wait to stay under the rate.
+    fn take(&self, amount: u64) -> Duration {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate as f64).min(rate as f64);
+        *last = now;
+        *tokens -= amount as f64;
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / rate as f64)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// How often the schedules are checked
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Limits the rate at which bytes are copied and operations are done, shared
+/// by all threads, see --bwlimit and --iops-limit.
+pub struct Throttle {
+    bytes: TokenBucket,
+    operations: TokenBucket,
+    bytes_schedule: Mutex<Schedule>,
+    operations_schedule: Mutex<Schedule>,
+    stats: Arc<Stats>,
+}
+
+impl Throttle {
+    pub fn new(stats: Arc<Stats>) -> Throttle {
+        Throttle {
+            bytes: TokenBucket::new(),
+            operations: TokenBucket::new(),
+            bytes_schedule: Mutex::new(Schedule::default()),
+            operations_schedule: Mutex::new(Schedule::default()),
+            stats,
+        }
+    }
+
+    pub fn set_bandwidth(&self, schedule: Schedule) {
+        *self.bytes_schedule.lock().unwrap() = schedule;
+        self.update();
+    }
+
+    pub fn set_iops(&self, schedule: Schedule) {
+        *self.operations_schedule.lock().unwrap() = schedule;
+        self.update();
+    }
+
+    /// Follow the time-of-day schedules until the sync is over.
+    pub fn follow_schedules(&self) {
+        while !self.stats.done() {
+            std::thread::sleep(SCHEDULE_INTERVAL);
+            self.update();
+        }
+    }
+
+    /// Apply the limits for the current time of day.
+    fn update(&self) {
+        let time = local_time_of_day();
+        let bytes = self.bytes_schedule.lock().unwrap().rate_at(time);
+        let operations = self.operations_schedule.lock().unwrap().rate_at(time);
+        let previous_bytes = self.bytes.rate.swap(bytes, Ordering::Relaxed);
+        let previous_operations = self.operations.rate.swap(operations, Ordering::Relaxed);
+        if (bytes, operations) != (previous_bytes, previous_operations) {
+            info!(
+                "Throttling to {}/s and {} operations/s (0 is unlimited)",
+                format_bytes(bytes),
+                operations,
+            );
+        }
+        self.stats.set_throttle_limits(bytes, operations);
+    }
+
+    fn wait(&self, wait: Duration, worker: &Worker) {
+        if !wait.is_zero() {
+            self.stats.add_throttled_time(wait);
+            worker.sleep(wait);
+        }
+    }
+
+    /// Account for bytes written, sleeping if we're going too fast.
+    pub fn consume(&self, bytes: u64, worker: &Worker) {
+        self.wait(self.bytes.take(bytes), worker);
+    }
+
+    /// Account for an operation (opening a file, reading metadata), sleeping
+    /// if we're going too fast.
+    pub fn operation(&self, worker: &Worker) {
+        self.wait(self.operations.take(1), worker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Schedule, parse_size};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0", 1024), Some(0));
+        assert_eq!(parse_size("100", 1024), Some(100));
+        assert_eq!(parse_size("10k", 1024), Some(10 * 1024));
+        assert_eq!(parse_size("10K", 1000), Some(10_000));
+        assert_eq!(parse_size("1.5M", 1024), Some(1536 * 1024));
+        assert_eq!(parse_size("2G", 1024), Some(2 << 30));
+        assert_eq!(parse_size("1T", 1024), Some(1 << 40));
+        assert_eq!(parse_size("4MiB", 1024), Some(4 << 20));
+        assert_eq!(parse_size("4mb", 1024), Some(4 << 20));
+        assert_eq!(parse_size(" 8k ", 1024), Some(8192));
+
+        assert_eq!(parse_size("", 1024), None);
+        assert_eq!(parse_size("k", 1024), None);
+        assert_eq!(parse_size("10x", 1024), None);
+        assert_eq!(parse_size("10P", 1024), None);
+        assert_eq!(parse_size("-1", 1024), None);
+        assert_eq!(parse_size("inf", 1024), None);
+        assert_eq!(parse_size("NaN", 1024), None);
+    }
+
+    #[test]
+    fn size_overflow() {
+        assert_eq!(parse_size("16777215T", 1024), Some(16777215 << 40));
+        assert_eq!(parse_size("16777216T", 1024), None);
+        assert_eq!(parse_size("18446744073709551616", 1024), None);
+        assert_eq!(parse_size("1e30", 1000), None);
+    }
+
+    #[test]
+    fn schedule() {
+        let schedule = Schedule::parse("08:00-20:00=10M,1M", 1024).unwrap();
+        assert_eq!(schedule.rate_at(0), 1 << 20);
+        assert_eq!(schedule.rate_at(8 * 60 - 1), 1 << 20);
+        assert_eq!(schedule.rate_at(8 * 60), 10 << 20);
+        assert_eq!(schedule.rate_at(20 * 60 - 1), 10 << 20);
+        assert_eq!(schedule.rate_at(20 * 60), 1 << 20);
+
+        // No default rate is unlimited
+        let schedule = Schedule::parse("08:00-20:00=100", 1000).unwrap();
+        assert_eq!(schedule.rate_at(12 * 60), 100);
+        assert_eq!(schedule.rate_at(21 * 60), 0);
+
+        // The first matching window applies
+        let schedule = Schedule::parse("00:00-24:00=5, 10:00-11:00=7", 1000).unwrap();
+        assert_eq!(schedule.rate_at(10 * 60 + 30), 5);
+    }
+
+    #[test]
+    fn schedule_past_midnight() {
+        let schedule = Schedule::parse("0,22:00-06:00=10k", 1000).unwrap();
+        assert_eq!(schedule.rate_at(22 * 60 - 1), 0);
+        assert_eq!(schedule.rate_at(22 * 60), 10_000);
+        assert_eq!(schedule.rate_at(23 * 60 + 59), 10_000);
+        assert_eq!(schedule.rate_at(0), 10_000);
+        assert_eq!(schedule.rate_at(6 * 60 - 1), 10_000);
+        assert_eq!(schedule.rate_at(6 * 60), 0);
+        assert_eq!(schedule.rate_at(12 * 60), 0);
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        assert!(Schedule::parse("1M,2M", 1024).is_none());
+        assert!(Schedule::parse("08:00=1M", 1024).is_none());
+        assert!(Schedule::parse("08:00-25:00=1M", 1024).is_none());
+        assert!(Schedule::parse("08:60-09:00=1M", 1024).is_none());
+        assert!(Schedule::parse("24:01-01:00=1M", 1024).is_none());
+        assert!(Schedule::parse("08:00-09:00=fast", 1024).is_none());
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+use crate::control::Control;
+use crate::stats::{Stats, format_duration};
+
+/// How often the workers are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watch for operations that take too long, for example because a network
+/// filesystem is hung.
+///
+/// Operations that made no progress for `op_timeout` are logged once and
+/// counted as stalled. If no worker made any progress for `stall_timeout`,
+/// `on_stall` is called and the watchdog stops. Time spent paused, held back
+/// by the throttle or waiting to retry doesn't count.
+pub fn start(
+    stats: Arc<Stats>,
+    control: Arc<Control>,
+    op_timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    on_stall: impl FnOnce() + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        // Operations already logged, by worker and start time
+        let mut reported = HashSet::<(usize, Instant)>::new();
+        // When the sync was last paused, nothing is expected to progress
+        // before that
+        let mut paused_at = None;
+
+        loop {
+            std::thread::sleep(CHECK_INTERVAL);
+            if stats.done() {
+                return;
+            }
+
+            if control.paused() || control.copies_paused() {
+                paused_at = Some(Instant::now());
+                reported.clear();
+                stats.set_stalled_workers(0);
+                continue;
+            }
+            let since_progress = |last_progress: Instant| match paused_at {
+                Some(paused_at) => last_progress.max(paused_at).elapsed(),
+                None => last_progress.elapsed(),
+            };
+
+            let workers = stats.worker_activity();
+
+            if let Some(op_timeout) = op_timeout {
+                let mut stuck = HashSet::new();
+                for worker in &workers {
+                    let (Some(path), Some(operation)) = (&worker.path, worker.operation) else {
+                        continue;
+                    };
+                    if since_progress(worker.last_progress) <= op_timeout {
+                        continue;
+                    }
+                    let key = (worker.id, worker.since);
+                    if !reported.contains(&key) {
+                        warn!(
+                            "Operation stuck for {}: {} {:?} ({} thread {})",
+                            format_duration(since_progress(worker.last_progress)),
+                            operation.description(),
+                            path,
+                            worker.kind,
+                            worker.id,
+                        );
+                    }
+                    stuck.insert(key);
+                }
+                stats.set_stalled_workers(stuck.len());
+                reported = stuck;
+            }
+
+            if let Some(stall_timeout) = stall_timeout {
+                let last_progress = workers.iter().map(|w| w.last_progress).max();
+                if last_progress.is_some_and(|t| since_progress(t) > stall_timeout) {
+                    error!("No progress for {}, aborting", format_duration(stall_timeout));
+                    eprint!("{}", stats.status_report());
+                    on_stall();
+                    return;
+                }
+            }
+        }
+    });
+}